@@ -0,0 +1,16 @@
+-- This migration is the schema of the former `postgres/init.sql` script, created
+-- only if missing so that databases created by that script are adopted as-is. The
+-- later migrations bring both kinds of databases to the same schema.
+
+CREATE TABLE IF NOT EXISTS users(
+    id         SERIAL PRIMARY KEY,
+    password   VARCHAR(50) NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS messages(
+    id         SERIAL PRIMARY KEY,
+    author     INT4 REFERENCES users (id),
+    text       VARCHAR(100) NOT NULL,
+    created    INT8 NOT NULL,
+    modified   INT8
+);
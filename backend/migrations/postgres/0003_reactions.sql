@@ -0,0 +1,7 @@
+CREATE TABLE reactions(
+    message    INT4 NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    "user"     INT4 NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    emoji      VARCHAR(32) NOT NULL,
+    created    INT8 NOT NULL,
+    PRIMARY KEY (message, "user", emoji)
+);
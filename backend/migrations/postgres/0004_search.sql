@@ -0,0 +1,2 @@
+-- Index the english text search vector of messages, for full-text search.
+CREATE INDEX messages_search ON messages USING GIN (to_tsvector('english', text));
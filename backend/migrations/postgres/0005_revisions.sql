@@ -0,0 +1,11 @@
+ALTER TABLE messages ADD COLUMN edit_count INT4 NOT NULL DEFAULT 0;
+
+CREATE TABLE message_revisions(
+    id         SERIAL PRIMARY KEY,
+    message    INT4 NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    text       TEXT NOT NULL,
+    created    INT8 NOT NULL,
+    replaced   INT8 NOT NULL
+);
+
+CREATE INDEX message_revisions_message ON message_revisions (message, id);
@@ -0,0 +1,2 @@
+-- Deleted messages are kept as tombstones until purged.
+ALTER TABLE messages ADD COLUMN deleted_at INT8;
@@ -0,0 +1,3 @@
+-- The initial schema limited messages to 100 characters. Lengths are now enforced
+-- by the moderation policy.
+ALTER TABLE messages ALTER COLUMN text TYPE TEXT;
@@ -0,0 +1,10 @@
+-- Events broadcast to websocket clients are logged, so that clients can catch up
+-- on the events they missed while disconnected.
+CREATE TABLE events(
+    seq        BIGSERIAL PRIMARY KEY,
+    room       INT4 NOT NULL,
+    created    INT8 NOT NULL,
+    payload    TEXT NOT NULL
+);
+
+CREATE INDEX events_room ON events (room, seq);
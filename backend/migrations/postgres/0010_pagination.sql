@@ -0,0 +1,2 @@
+-- Messages are paginated by creation time, then by ID.
+CREATE INDEX messages_created_id ON messages (created, id);
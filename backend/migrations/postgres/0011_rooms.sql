@@ -0,0 +1,13 @@
+CREATE TABLE rooms(
+    id         SERIAL PRIMARY KEY,
+    name       VARCHAR(50) UNIQUE NOT NULL,
+    created    INT8 NOT NULL
+);
+
+INSERT INTO rooms(name, created) VALUES ('general', 0);
+
+-- Existing messages were posted before rooms existed, so they belong to the
+-- default room.
+ALTER TABLE messages ADD COLUMN room INT4 NOT NULL DEFAULT 1 REFERENCES rooms (id);
+
+CREATE INDEX messages_room_created_id ON messages (room, created, id);
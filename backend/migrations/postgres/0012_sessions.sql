@@ -0,0 +1,9 @@
+-- Passwords are stored as argon2 hashes, which are longer than 50 characters.
+ALTER TABLE users ALTER COLUMN password TYPE VARCHAR(200);
+
+CREATE TABLE sessions(
+    token      CHAR(64) PRIMARY KEY,
+    owner      INT4 NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created    INT8 NOT NULL,
+    expires    INT8 NOT NULL
+);
@@ -0,0 +1,10 @@
+ALTER TABLE users ADD COLUMN name VARCHAR(30);
+ALTER TABLE users ADD COLUMN color CHAR(7);
+ALTER TABLE users ADD COLUMN bio VARCHAR(200);
+
+-- Existing users were created before names existed, so they get a unique
+-- placeholder name, which they can change in their profile.
+UPDATE users SET name = 'user' || id;
+ALTER TABLE users ALTER COLUMN name SET NOT NULL;
+
+CREATE UNIQUE INDEX users_name ON users (LOWER(name));
@@ -0,0 +1,37 @@
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    password TEXT NOT NULL,
+    name TEXT NOT NULL,
+    color TEXT,
+    bio TEXT
+);
+
+CREATE UNIQUE INDEX users_name ON users (LOWER(name));
+
+CREATE TABLE sessions (
+    token TEXT PRIMARY KEY,
+    owner INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created INTEGER NOT NULL,
+    expires INTEGER NOT NULL
+);
+
+CREATE TABLE rooms (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT UNIQUE NOT NULL,
+    created INTEGER NOT NULL
+);
+
+INSERT INTO rooms (name, created) VALUES ('general', 0);
+
+CREATE TABLE messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    author INTEGER REFERENCES users (id),
+    room INTEGER NOT NULL DEFAULT 1 REFERENCES rooms (id),
+    text TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    modified INTEGER
+);
+
+CREATE INDEX messages_created_id ON messages (created, id);
+
+CREATE INDEX messages_room_created_id ON messages (room, created, id);
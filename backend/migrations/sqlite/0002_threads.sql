@@ -0,0 +1,4 @@
+ALTER TABLE messages ADD COLUMN parent INTEGER REFERENCES messages (id) ON DELETE CASCADE;
+ALTER TABLE messages ADD COLUMN reply_count INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX messages_parent_created_id ON messages (parent, created, id);
@@ -0,0 +1,7 @@
+CREATE TABLE reactions (
+    message INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    "user" INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    emoji TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    PRIMARY KEY (message, "user", emoji)
+);
@@ -0,0 +1,11 @@
+ALTER TABLE messages ADD COLUMN edit_count INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE message_revisions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    text TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    replaced INTEGER NOT NULL
+);
+
+CREATE INDEX message_revisions_message ON message_revisions (message, id);
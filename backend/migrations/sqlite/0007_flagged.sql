@@ -0,0 +1,2 @@
+-- Messages flagged for review by the moderation policy.
+ALTER TABLE messages ADD COLUMN flagged BOOLEAN NOT NULL DEFAULT 0;
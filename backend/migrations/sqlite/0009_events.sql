@@ -0,0 +1,11 @@
+-- Events broadcast to websocket clients are logged, so that clients can catch up
+-- on the events they missed while disconnected. AUTOINCREMENT guarantees that
+-- sequence numbers are never reused, even after the latest events are purged.
+CREATE TABLE events (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    room INTEGER NOT NULL,
+    created INTEGER NOT NULL,
+    payload TEXT NOT NULL
+);
+
+CREATE INDEX events_room ON events (room, seq);
@@ -0,0 +1,457 @@
+//! This module implements the [`ChatStore`] in memory, mostly for tests and demos.
+//!
+//! Nothing is persisted: all documents are lost when the server stops.
+
+use super::moderation::Moderated;
+use super::search::SearchTerms;
+use super::{ChatStore, DatabaseError, Id, Message, NewProfile, PageQuery, Profile, Result};
+use super::{LoggedEvent, Seq, Timestamp, DEFAULT_ROOM};
+use super::{MessageRow, NewMessage, ReactionCount, Revision, Room, SearchQuery, SearchResult};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+/// This struct stores all documents in memory, behind a single lock.
+pub struct MemoryStore {
+    tables: Mutex<Tables>,
+}
+
+/// This struct holds the documents of a [`MemoryStore`], indexed like the postgres
+/// schema with IDs assigned sequentially from 1.
+#[derive(Default)]
+struct Tables {
+    users: BTreeMap<Id, (String, Profile)>,
+    sessions: HashMap<String, (Id, Timestamp)>,
+    rooms: BTreeMap<Id, Room>,
+    messages: BTreeMap<Id, MessageRow>,
+    reactions: BTreeMap<(Id, Id, String), Timestamp>,
+    revisions: BTreeMap<Id, Revision>,
+    events: BTreeMap<Seq, LoggedEvent>,
+    next_user: Id,
+    next_room: Id,
+    next_message: Id,
+    next_revision: Id,
+    next_event: Seq,
+}
+
+impl MemoryStore {
+    /// This constructor initializes an empty store with the default room.
+    pub fn new() -> Self {
+        let mut tables = Tables {
+            next_user: 1,
+            next_room: DEFAULT_ROOM + 1,
+            next_message: 1,
+            next_revision: 1,
+            next_event: 1,
+            ..Tables::default()
+        };
+        let general = Room {
+            id: DEFAULT_ROOM,
+            name: "general".to_string(),
+            created: 0,
+        };
+        tables.rooms.insert(DEFAULT_ROOM, general);
+        Self {
+            tables: Mutex::new(tables),
+        }
+    }
+}
+
+impl Tables {
+    /// This method validates that no other user than `id` has the input name,
+    /// regardless of case.
+    fn validate_unique_name(&self, id: Option<Id>, name: &str) -> Result<()> {
+        let name = name.to_lowercase();
+        let taken = self
+            .users
+            .values()
+            .any(|(_, profile)| Some(profile.id) != id && profile.name.to_lowercase() == name);
+        if taken {
+            return Err(DatabaseError::Validation(
+                "Name is already taken".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ChatStore for MemoryStore {
+    async fn create_user(&self, password: String, profile: NewProfile) -> Result<Id> {
+        let mut tables = self.tables.lock();
+        tables.validate_unique_name(None, &profile.name)?;
+        let id = tables.next_user;
+        tables.next_user += 1;
+        let profile = Profile {
+            id,
+            name: profile.name,
+            color: profile.color,
+            bio: profile.bio,
+        };
+        tables.users.insert(id, (password, profile));
+        Ok(id)
+    }
+
+    async fn read_password(&self, id: Id) -> Result<Option<String>> {
+        let tables = self.tables.lock();
+        Ok(tables.users.get(&id).map(|(password, _)| password.clone()))
+    }
+
+    async fn update_password(&self, id: Id, password: String) -> Result<()> {
+        let mut tables = self.tables.lock();
+        match tables.users.get_mut(&id) {
+            Some((stored, _)) => {
+                *stored = password;
+                Ok(())
+            }
+            None => Err(missing("user")),
+        }
+    }
+
+    async fn read_user(&self, id: Id) -> Result<Option<Profile>> {
+        let tables = self.tables.lock();
+        Ok(tables.users.get(&id).map(|(_, profile)| profile.clone()))
+    }
+
+    async fn read_users(&self, ids: &[Id]) -> Result<Vec<Profile>> {
+        let tables = self.tables.lock();
+        let users = ids.iter().filter_map(|id| tables.users.get(id));
+        Ok(users.map(|(_, profile)| profile.clone()).collect())
+    }
+
+    async fn update_user(&self, profile: Profile) -> Result<Profile> {
+        let mut tables = self.tables.lock();
+        tables.validate_unique_name(Some(profile.id), &profile.name)?;
+        match tables.users.get_mut(&profile.id) {
+            Some((_, stored)) => {
+                *stored = profile.clone();
+                Ok(profile)
+            }
+            None => Err(missing("user")),
+        }
+    }
+
+    async fn create_session(
+        &self,
+        token: String,
+        user: Id,
+        _: Timestamp,
+        expires: Timestamp,
+    ) -> Result<()> {
+        let mut tables = self.tables.lock();
+        tables.sessions.insert(token, (user, expires));
+        Ok(())
+    }
+
+    async fn read_session(&self, token: &str, now: Timestamp) -> Result<Option<Id>> {
+        let tables = self.tables.lock();
+        let session = tables.sessions.get(token);
+        Ok(session
+            .filter(|(_, expires)| *expires > now)
+            .map(|(user, _)| *user))
+    }
+
+    async fn delete_session(&self, token: &str) -> Result<()> {
+        self.tables.lock().sessions.remove(token);
+        Ok(())
+    }
+
+    async fn read_rooms(&self) -> Result<Vec<Room>> {
+        let tables = self.tables.lock();
+        let mut rooms: Vec<Room> = tables.rooms.values().cloned().collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(rooms)
+    }
+
+    async fn read_room(&self, id: Id) -> Result<Option<Room>> {
+        Ok(self.tables.lock().rooms.get(&id).cloned())
+    }
+
+    async fn create_room(&self, name: String, created: Timestamp) -> Result<Room> {
+        let mut tables = self.tables.lock();
+        if tables.rooms.values().any(|room| room.name == name) {
+            return Err(DatabaseError::Validation("Room already exists".to_string()));
+        }
+        let id = tables.next_room;
+        tables.next_room += 1;
+        let room = Room { id, name, created };
+        tables.rooms.insert(id, room.clone());
+        Ok(room)
+    }
+
+    async fn read_messages(&self, query: PageQuery) -> Result<Vec<Message>> {
+        let tables = self.tables.lock();
+        let mut messages: Vec<&MessageRow> = tables
+            .messages
+            .values()
+            .filter(|message| message.parent.is_none())
+            .filter(|message| query.room.filter(|room| *room != message.room).is_none())
+            .filter(|message| match query.cursor {
+                Some(cursor) if query.forward => {
+                    (message.created, message.id) > (cursor.created, cursor.id)
+                }
+                Some(cursor) => (message.created, message.id) < (cursor.created, cursor.id),
+                None => true,
+            })
+            .collect();
+        messages.sort_by_key(|message| (message.created, message.id));
+        if !query.forward {
+            messages.reverse();
+        }
+        let limit = query.limit as usize;
+        let messages = messages.into_iter().take(limit).cloned();
+        Ok(messages.map(Message::from).collect())
+    }
+
+    async fn read_message(&self, id: Id) -> Result<Option<Message>> {
+        let tables = self.tables.lock();
+        Ok(tables.messages.get(&id).cloned().map(Message::from))
+    }
+
+    async fn read_revisions(&self, id: Id) -> Result<Vec<Revision>> {
+        let tables = self.tables.lock();
+        let revisions = tables.revisions.values();
+        let revisions = revisions.filter(|revision| revision.message == id);
+        Ok(revisions.cloned().collect())
+    }
+
+    async fn search_messages(&self, query: SearchQuery) -> Result<Vec<SearchResult>> {
+        let tables = self.tables.lock();
+        let messages = tables
+            .messages
+            .values()
+            .filter(|message| message.deleted_at.is_none())
+            .filter(|message| {
+                query
+                    .author
+                    .filter(|author| *author != message.author)
+                    .is_none()
+            })
+            .filter(|message| query.room.filter(|room| *room != message.room).is_none())
+            .filter(|message| {
+                query
+                    .since
+                    .filter(|since| *since > message.created)
+                    .is_none()
+            })
+            .filter(|message| {
+                query
+                    .until
+                    .filter(|until| *until <= message.created)
+                    .is_none()
+            })
+            .cloned()
+            .map(Message::from);
+        Ok(SearchTerms::new(&query.text).search(messages, query.limit))
+    }
+
+    async fn read_replies(&self, id: Id) -> Result<Vec<Message>> {
+        let tables = self.tables.lock();
+        let mut replies: Vec<MessageRow> = tables
+            .messages
+            .values()
+            .filter(|message| message.parent == Some(id))
+            .cloned()
+            .collect();
+        replies.sort_by_key(|message| (message.created, message.id));
+        Ok(replies.into_iter().map(Message::from).collect())
+    }
+
+    async fn create_message(&self, message: NewMessage) -> Result<Message> {
+        let mut tables = self.tables.lock();
+        if let Some(parent) = message.parent {
+            let parent = tables
+                .messages
+                .get_mut(&parent)
+                .ok_or_else(|| missing("thread"))?;
+            parent.reply_count += 1;
+        }
+        let id = tables.next_message;
+        tables.next_message += 1;
+        let message = MessageRow {
+            id,
+            author: message.author,
+            room: message.room,
+            text: message.text,
+            created: message.created,
+            modified: None,
+            parent: message.parent,
+            reply_count: 0,
+            edit_count: 0,
+            flagged: message.flagged,
+            deleted_at: None,
+        };
+        tables.messages.insert(id, message.clone());
+        Ok(message.into())
+    }
+
+    async fn update_message(
+        &self,
+        id: Id,
+        text: Moderated,
+        modified: Timestamp,
+    ) -> Result<Message> {
+        let mut guard = self.tables.lock();
+        let tables = &mut *guard;
+        let message = tables
+            .messages
+            .get_mut(&id)
+            .ok_or_else(|| missing("message"))?;
+        let revision = Revision {
+            id: tables.next_revision,
+            message: id,
+            text: std::mem::replace(&mut message.text, text.text),
+            created: message.modified.unwrap_or(message.created),
+            replaced: modified,
+        };
+        tables.next_revision += 1;
+        tables.revisions.insert(revision.id, revision);
+        message.modified = Some(modified);
+        message.edit_count += 1;
+        message.flagged |= text.flagged;
+        Ok(message.clone().into())
+    }
+
+    async fn delete_message(&self, id: Id, deleted: Timestamp) -> Result<Message> {
+        let mut tables = self.tables.lock();
+        let message = tables
+            .messages
+            .get_mut(&id)
+            .filter(|message| message.deleted_at.is_none())
+            .ok_or_else(|| missing("message"))?;
+        message.deleted_at = Some(deleted);
+        let message = message.clone();
+        if let Some(parent) = message.parent.and_then(|id| tables.messages.get_mut(&id)) {
+            parent.reply_count -= 1;
+        }
+        Ok(message.into())
+    }
+
+    async fn restore_message(&self, id: Id, deleted_since: Timestamp) -> Result<Option<Message>> {
+        let mut tables = self.tables.lock();
+        let message = tables.messages.get_mut(&id).filter(
+            |message| matches!(message.deleted_at, Some(deleted) if deleted >= deleted_since),
+        );
+        let message = match message {
+            Some(message) => {
+                message.deleted_at = None;
+                message.clone()
+            }
+            None => return Ok(None),
+        };
+        if let Some(parent) = message.parent.and_then(|id| tables.messages.get_mut(&id)) {
+            parent.reply_count += 1;
+        }
+        Ok(Some(message.into()))
+    }
+
+    async fn purge_messages(&self, deleted_before: Timestamp) -> Result<u64> {
+        let mut guard = self.tables.lock();
+        let tables = &mut *guard;
+        let parents: Vec<Id> = tables.messages.values().filter_map(|m| m.parent).collect();
+        let count = tables.messages.len();
+        tables.messages.retain(|id, message| {
+            let expired = matches!(message.deleted_at, Some(deleted) if deleted < deleted_before);
+            !expired || parents.contains(id)
+        });
+        let purged = count - tables.messages.len();
+        let messages = &tables.messages;
+        tables
+            .reactions
+            .retain(|(message, _, _), _| messages.contains_key(message));
+        tables
+            .revisions
+            .retain(|_, revision| messages.contains_key(&revision.message));
+        Ok(purged as u64)
+    }
+
+    async fn create_reaction(
+        &self,
+        message: Id,
+        user: Id,
+        emoji: String,
+        created: Timestamp,
+    ) -> Result<()> {
+        let mut tables = self.tables.lock();
+        tables
+            .reactions
+            .entry((message, user, emoji))
+            .or_insert(created);
+        Ok(())
+    }
+
+    async fn delete_reaction(&self, message: Id, user: Id, emoji: &str) -> Result<bool> {
+        let mut tables = self.tables.lock();
+        let key = (message, user, emoji.to_string());
+        Ok(tables.reactions.remove(&key).is_some())
+    }
+
+    async fn read_reactions(&self, messages: &[Id]) -> Result<Vec<ReactionCount>> {
+        let tables = self.tables.lock();
+        // Aggregate the count and first occurrence of each emoji per message.
+        let mut counts: HashMap<(Id, &str), (i64, Timestamp)> = HashMap::new();
+        for ((message, _, emoji), created) in &tables.reactions {
+            if messages.contains(message) {
+                let count = counts.entry((*message, emoji)).or_insert((0, *created));
+                count.0 += 1;
+                count.1 = count.1.min(*created);
+            }
+        }
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by_key(|((_, emoji), (_, created))| (*created, *emoji));
+        let reactions = counts
+            .into_iter()
+            .map(|((message, emoji), (count, _))| ReactionCount {
+                message,
+                emoji: emoji.to_string(),
+                count,
+            });
+        Ok(reactions.collect())
+    }
+
+    async fn create_event(&self, room: Id, payload: String, created: Timestamp) -> Result<Seq> {
+        let mut tables = self.tables.lock();
+        let seq = tables.next_event;
+        tables.next_event += 1;
+        let event = LoggedEvent {
+            seq,
+            room,
+            created,
+            payload,
+        };
+        tables.events.insert(seq, event);
+        Ok(seq)
+    }
+
+    async fn read_events(&self, since: Seq, rooms: &[Id], limit: i64) -> Result<Vec<LoggedEvent>> {
+        let tables = self.tables.lock();
+        let range = (Bound::Excluded(since), Bound::Unbounded);
+        let events = tables.events.range(range).map(|(_, event)| event);
+        let events = events.filter(|event| rooms.is_empty() || rooms.contains(&event.room));
+        Ok(events.take(limit as usize).cloned().collect())
+    }
+
+    async fn read_oldest_event(&self) -> Result<Option<Seq>> {
+        let tables = self.tables.lock();
+        Ok(tables.events.keys().next().copied())
+    }
+
+    async fn read_last_seq(&self) -> Result<Seq> {
+        Ok(self.tables.lock().next_event - 1)
+    }
+
+    async fn purge_events(&self, created_before: Timestamp) -> Result<u64> {
+        let mut tables = self.tables.lock();
+        let count = tables.events.len();
+        tables
+            .events
+            .retain(|_, event| event.created >= created_before);
+        Ok((count - tables.events.len()) as u64)
+    }
+}
+
+/// This function returns the storage error of a document that was expected to exist.
+fn missing(document: &str) -> DatabaseError {
+    DatabaseError::Storage(format!("Failed to find {}", document))
+}
@@ -0,0 +1,1255 @@
+//! This module is responsible for the database that stores all messages and users.
+//!
+//! The [`Database`] validates and authenticates all operations, and delegates the
+//! storage itself to a [`ChatStore`] selected at startup from the database URL.
+
+mod memory;
+mod moderation;
+mod postgres;
+mod search;
+mod sqlite;
+
+pub use moderation::PolicyConfig;
+
+use crate::config::Config;
+use crate::database::moderation::{Moderated, ModerationPolicy};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use async_trait::async_trait;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// A timestamp is the number of seconds since 1970-01-01 00:00:00 UTC.
+///
+/// This number should always be much smaller than 2^53 - 1, which is
+/// the biggest integer that can safely be represented in JavaScript.
+pub type Timestamp = i64;
+
+/// This type alias is used by database methods that are fallible.
+pub type Result<T> = std::result::Result<T, DatabaseError>;
+
+/// This enum represents all the ways a database method can fail.
+///
+/// Each variant carries a human-readable error message that is safe to
+/// forward to the client, except for `Storage`, whose message may contain
+/// details of the underlying storage and must only be logged.
+#[derive(Debug)]
+pub enum DatabaseError {
+    /// The requested document doesn't exist.
+    NotFound(String),
+    /// The user credentials are missing or invalid.
+    Unauthorized(String),
+    /// The user is authenticated but isn't allowed to perform the operation.
+    Forbidden(String),
+    /// The user input is invalid.
+    Validation(String),
+    /// The underlying storage failed, which should normally never happen.
+    /// Its message is meant for the server logs, not for the client.
+    Storage(String),
+}
+
+impl std::fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DatabaseError::NotFound(message)
+            | DatabaseError::Unauthorized(message)
+            | DatabaseError::Forbidden(message)
+            | DatabaseError::Validation(message)
+            | DatabaseError::Storage(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+/// An ID is a unique 32-bit unsigned integer.
+pub type Id = i32;
+
+/// A sequence number is a unique 64-bit integer, increasing with each logged event.
+pub type Seq = i64;
+
+/// This struct represents the chitchat database to store all messages and users.
+pub struct Database {
+    store: Box<dyn ChatStore>,
+    /// The moderation policy applied to all texts, swapped when it's reloaded.
+    policy: RwLock<Arc<ModerationPolicy>>,
+    policy_path: Option<PathBuf>,
+    max_text_length: usize,
+    restore_window: i64,
+    message_retention: i64,
+}
+
+/// This trait abstracts the storage of all messages, rooms, sessions and users.
+///
+/// Implementations only store and retrieve documents: all validation, hashing
+/// and authorization happens in [`Database`] before calling them.
+#[async_trait]
+pub trait ChatStore: Send + Sync {
+    /// This method inserts a new user and returns their ID. It fails with a
+    /// validation error if the name is already taken, regardless of case.
+    async fn create_user(&self, password: String, profile: NewProfile) -> Result<Id>;
+
+    /// This method returns the password hash of the user, if they exist.
+    async fn read_password(&self, id: Id) -> Result<Option<String>>;
+
+    /// This method replaces the password hash of an existing user.
+    async fn update_password(&self, id: Id, password: String) -> Result<()>;
+
+    /// This method returns the profile of the user, if they exist.
+    async fn read_user(&self, id: Id) -> Result<Option<Profile>>;
+
+    /// This method returns the profiles of the input users that exist.
+    async fn read_users(&self, ids: &[Id]) -> Result<Vec<Profile>>;
+
+    /// This method replaces the profile of an existing user and returns it. It
+    /// fails with a validation error if the name is already taken.
+    async fn update_user(&self, profile: Profile) -> Result<Profile>;
+
+    /// This method inserts a new session identified by the hash of its token.
+    async fn create_session(
+        &self,
+        token: String,
+        user: Id,
+        created: Timestamp,
+        expires: Timestamp,
+    ) -> Result<()>;
+
+    /// This method returns the user of the session, if it exists and hasn't expired.
+    async fn read_session(&self, token: &str, now: Timestamp) -> Result<Option<Id>>;
+
+    /// This method deletes the session, if it exists.
+    async fn delete_session(&self, token: &str) -> Result<()>;
+
+    /// This method returns all rooms sorted by name.
+    async fn read_rooms(&self) -> Result<Vec<Room>>;
+
+    /// This method returns the room, if it exists.
+    async fn read_room(&self, id: Id) -> Result<Option<Room>>;
+
+    /// This method inserts a new room and returns it. It fails with a validation
+    /// error if the name is already taken.
+    async fn create_room(&self, name: String, created: Timestamp) -> Result<Room>;
+
+    /// This method returns the top-level messages matching the query, sorted in the
+    /// order they are read, i.e. anti-chronological unless `query.forward` is set.
+    async fn read_messages(&self, query: PageQuery) -> Result<Vec<Message>>;
+
+    /// This method returns the message, if it exists.
+    async fn read_message(&self, id: Id) -> Result<Option<Message>>;
+
+    /// This method returns the revisions of the message in chronological order.
+    async fn read_revisions(&self, id: Id) -> Result<Vec<Revision>>;
+
+    /// This method returns the best messages matching the full-text search, sorted
+    /// by decreasing rank and then anti-chronologically.
+    async fn search_messages(&self, query: SearchQuery) -> Result<Vec<SearchResult>>;
+
+    /// This method returns the replies to the message, sorted in chronological order.
+    async fn read_replies(&self, id: Id) -> Result<Vec<Message>>;
+
+    /// This method inserts a new message and returns it. If the message is a
+    /// reply, the reply count of its parent is incremented.
+    async fn create_message(&self, message: NewMessage) -> Result<Message>;
+
+    /// This method saves the current text of an existing message as a revision, then
+    /// updates it with the moderated text and returns it.
+    async fn update_message(&self, id: Id, text: Moderated, modified: Timestamp)
+        -> Result<Message>;
+
+    /// This method marks an existing message as deleted, and returns its tombstone.
+    /// If the message is a reply, the reply count of its parent is decremented.
+    async fn delete_message(&self, id: Id, deleted: Timestamp) -> Result<Message>;
+
+    /// This method unmarks a message deleted at or after `deleted_since`, and returns
+    /// it. If the message is a reply, the reply count of its parent is incremented.
+    /// It returns `None` if there is no such message.
+    async fn restore_message(&self, id: Id, deleted_since: Timestamp) -> Result<Option<Message>>;
+
+    /// This method permanently deletes the messages deleted before `deleted_before`,
+    /// except those with replies, and returns how many were purged.
+    async fn purge_messages(&self, deleted_before: Timestamp) -> Result<u64>;
+
+    /// This method inserts a reaction of the user to an existing message, unless
+    /// the user already reacted with the same emoji.
+    async fn create_reaction(
+        &self,
+        message: Id,
+        user: Id,
+        emoji: String,
+        created: Timestamp,
+    ) -> Result<()>;
+
+    /// This method deletes the reaction of the user to the message, and returns
+    /// whether it existed.
+    async fn delete_reaction(&self, message: Id, user: Id, emoji: &str) -> Result<bool>;
+
+    /// This method returns the reactions to the input messages, aggregated by
+    /// message and emoji, and sorted by their first occurrence.
+    async fn read_reactions(&self, messages: &[Id]) -> Result<Vec<ReactionCount>>;
+
+    /// This method appends an event to the event log, and returns its sequence
+    /// number, which is greater than those of all events logged before.
+    async fn create_event(&self, room: Id, payload: String, created: Timestamp) -> Result<Seq>;
+
+    /// This method returns up to `limit` events logged after `since`, in the input
+    /// rooms or in all rooms if none is given, sorted by sequence number.
+    async fn read_events(&self, since: Seq, rooms: &[Id], limit: i64) -> Result<Vec<LoggedEvent>>;
+
+    /// This method returns the sequence number of the oldest event in the event
+    /// log, if it isn't empty.
+    async fn read_oldest_event(&self) -> Result<Option<Seq>>;
+
+    /// This method returns the sequence number of the last logged event, even if
+    /// it has been purged since, or 0 if no event has ever been logged.
+    async fn read_last_seq(&self) -> Result<Seq>;
+
+    /// This method permanently deletes the events logged before `created_before`,
+    /// and returns how many were purged.
+    async fn purge_events(&self, created_before: Timestamp) -> Result<u64>;
+}
+
+/// This struct represents a message document in the database.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Message {
+    pub id: Id,
+    pub author: Id,
+    pub room: Id,
+    pub text: String,
+    pub created: Timestamp,
+    pub modified: Option<Timestamp>,
+    /// The top-level message this message replies to, if any. Replies to replies
+    /// are attached to the top-level message, so threads are only one level deep.
+    pub parent: Option<Id>,
+    /// The number of replies to this message, which is always 0 for replies.
+    pub reply_count: i32,
+    /// The number of times this message was edited, i.e. its number of revisions.
+    pub edit_count: i32,
+    /// Whether this message was flagged for review by the moderation policy.
+    pub flagged: bool,
+    /// Whether this message was deleted, in which case it's a tombstone without
+    /// text nor reactions, kept so that its thread and its position stay intact.
+    pub deleted: bool,
+    /// The reactions to this message, aggregated by emoji and sorted by their
+    /// first occurrence.
+    pub reactions: Vec<Reaction>,
+}
+
+/// This struct represents a row of the messages table, i.e. a message without its
+/// reactions, which are stored in their own table.
+#[derive(Clone, FromRow)]
+struct MessageRow {
+    id: Id,
+    author: Id,
+    room: Id,
+    text: String,
+    created: Timestamp,
+    modified: Option<Timestamp>,
+    parent: Option<Id>,
+    reply_count: i32,
+    edit_count: i32,
+    flagged: bool,
+    deleted_at: Option<Timestamp>,
+}
+
+impl From<MessageRow> for Message {
+    fn from(row: MessageRow) -> Self {
+        let deleted = row.deleted_at.is_some();
+        Self {
+            id: row.id,
+            author: row.author,
+            room: row.room,
+            text: if deleted { String::new() } else { row.text },
+            created: row.created,
+            modified: row.modified,
+            parent: row.parent,
+            reply_count: row.reply_count,
+            edit_count: row.edit_count,
+            flagged: row.flagged,
+            deleted,
+            reactions: Vec::new(),
+        }
+    }
+}
+
+/// This struct represents an event broadcast to websocket clients, as saved in the
+/// event log so that clients can catch up on the events they missed.
+#[derive(Clone, Debug, FromRow)]
+pub struct LoggedEvent {
+    pub seq: Seq,
+    pub room: Id,
+    pub created: Timestamp,
+    /// The event serialized to JSON by the websocket module.
+    pub payload: String,
+}
+
+/// This struct represents a former text of a message, saved when it was edited.
+#[derive(Clone, Debug, Deserialize, FromRow, PartialEq, Serialize)]
+pub struct Revision {
+    pub id: Id,
+    pub message: Id,
+    pub text: String,
+    /// The time this text was written, i.e. when the message was created or last
+    /// modified before the edit.
+    pub created: Timestamp,
+    /// The time this text was replaced by the edit.
+    pub replaced: Timestamp,
+}
+
+/// This struct represents the reactions with the same emoji to a message.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: i64,
+}
+
+/// This struct represents the reactions with the same emoji to a message, as
+/// aggregated by a [`ChatStore`].
+#[derive(FromRow)]
+pub struct ReactionCount {
+    pub message: Id,
+    pub emoji: String,
+    pub count: i64,
+}
+
+/// This struct represents a chat room document in the database.
+#[derive(Clone, Debug, Deserialize, FromRow, PartialEq, Serialize)]
+pub struct Room {
+    pub id: Id,
+    pub name: String,
+    pub created: Timestamp,
+}
+
+/// This struct represents the credentials of a user.
+///
+/// Only a hash of the password is stored in the database, so the plaintext
+/// password is returned once when the user is created and never again.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct User {
+    pub id: Id,
+    pub password: String,
+}
+
+/// This struct represents the public profile of a user.
+#[derive(Clone, Debug, Deserialize, FromRow, PartialEq, Serialize)]
+pub struct Profile {
+    pub id: Id,
+    pub name: String,
+    /// The color of the user's avatar, formatted as `#rrggbb`.
+    pub color: Option<String>,
+    pub bio: Option<String>,
+}
+
+/// This struct contains the profile of a user about to be created.
+pub struct NewProfile {
+    pub name: String,
+    pub color: Option<String>,
+    pub bio: Option<String>,
+}
+
+/// This struct represents a session opened by a user with their credentials.
+///
+/// The token must be sent as a bearer token in the `Authorization` header of
+/// all authenticated requests. Only a hash of the token is stored in the database.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Session {
+    pub token: String,
+    pub user: Id,
+    pub expires: Timestamp,
+}
+
+/// This struct represents a position in the chronological order of messages.
+///
+/// It is serialized as an opaque string, e.g. `"1640995200.42"`, which clients
+/// should pass back as-is to read the next page of messages.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Cursor {
+    pub created: Timestamp,
+    pub id: Id,
+}
+
+impl From<&Message> for Cursor {
+    fn from(message: &Message) -> Self {
+        Self {
+            created: message.created,
+            id: message.id,
+        }
+    }
+}
+
+impl From<Cursor> for String {
+    fn from(cursor: Cursor) -> Self {
+        format!("{}.{}", cursor.created, cursor.id)
+    }
+}
+
+impl TryFrom<String> for Cursor {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        let invalid = || format!("Invalid cursor: {}", value);
+        let (created, id) = value.split_once('.').ok_or_else(invalid)?;
+        Ok(Self {
+            created: created.parse().map_err(|_| invalid())?,
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// This struct contains all the parameters needed to read a page of messages.
+///
+/// Without a cursor, the most recent messages are returned. With `before`, the
+/// messages right before the cursor are returned, and with `after`, the messages
+/// right after the cursor are returned.
+#[derive(Default, Deserialize, Serialize)]
+pub struct ReadMessages {
+    /// The room to read messages from. If `None`, messages from all rooms are read.
+    pub room: Option<Id>,
+    pub before: Option<Cursor>,
+    pub after: Option<Cursor>,
+    pub limit: Option<i64>,
+}
+
+/// This struct represents a page of messages sorted in chronological order.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessagePage {
+    pub messages: Vec<Message>,
+    /// The profiles of the authors of the messages, so that clients don't need
+    /// to look them up one by one.
+    pub users: Vec<Profile>,
+    /// The cursor to read the next page in the same direction, if any. That is,
+    /// it should be passed as `after` if this page was read with `after`, and as
+    /// `before` otherwise.
+    pub next_cursor: Option<Cursor>,
+}
+
+/// This struct contains the parameters of a full-text search of messages.
+#[derive(Default, Deserialize, Serialize)]
+pub struct SearchMessages {
+    /// The search query, e.g. `hello world`. Messages must match all its terms.
+    pub q: String,
+    /// The author of the messages. If `None`, messages from all authors are searched.
+    pub author: Option<Id>,
+    /// The room of the messages. If `None`, messages from all rooms are searched.
+    pub room: Option<Id>,
+    /// The creation time of the oldest messages to search, included.
+    pub since: Option<Timestamp>,
+    /// The creation time of the most recent messages to search, excluded.
+    pub until: Option<Timestamp>,
+    pub limit: Option<i64>,
+}
+
+/// This struct represents a message matching a full-text search.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct SearchResult {
+    pub message: Message,
+    /// The relevance of the message, which is higher for better matches.
+    pub rank: f32,
+    /// The text of the message escaped for HTML, with the matched words wrapped
+    /// in `<mark>` tags.
+    pub snippet: String,
+}
+
+/// This struct represents the results of a full-text search, sorted by decreasing
+/// rank and then anti-chronologically.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SearchResults {
+    pub results: Vec<SearchResult>,
+    /// The profiles of the authors of the messages.
+    pub users: Vec<Profile>,
+}
+
+/// This struct contains the validated parameters of a full-text search to run in
+/// a [`ChatStore`].
+pub struct SearchQuery {
+    pub text: String,
+    pub author: Option<Id>,
+    pub room: Option<Id>,
+    pub since: Option<Timestamp>,
+    pub until: Option<Timestamp>,
+    pub limit: i64,
+}
+
+/// This struct contains the validated parameters of a page of messages to read
+/// from a [`ChatStore`].
+pub struct PageQuery {
+    /// The room to read messages from. If `None`, messages from all rooms are read.
+    pub room: Option<Id>,
+    /// The position to read messages from, excluded. If `None`, messages are read
+    /// from the most recent one.
+    pub cursor: Option<Cursor>,
+    /// Whether messages are read in chronological order, i.e. after the cursor.
+    pub forward: bool,
+    /// The maximum number of messages to read.
+    pub limit: i64,
+}
+
+/// This struct contains the validated fields of a message about to be created.
+pub struct NewMessage {
+    pub author: Id,
+    pub room: Id,
+    pub parent: Option<Id>,
+    pub text: String,
+    pub flagged: bool,
+    pub created: Timestamp,
+}
+
+/// This struct represents a thread, i.e. a top-level message and its replies.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Thread {
+    pub message: Message,
+    /// The replies to the message, sorted in chronological order.
+    pub replies: Vec<Message>,
+    /// The profiles of the authors of the message and its replies.
+    pub users: Vec<Profile>,
+}
+
+/// This struct contains all the parameters needed to create a user.
+#[derive(Deserialize, Serialize)]
+pub struct CreateUser {
+    pub name: String,
+    pub color: Option<String>,
+    pub bio: Option<String>,
+}
+
+/// This struct contains all the parameters needed to update a user profile.
+///
+/// Fields that are `None` are left unchanged, while an empty color or bio is
+/// removed from the profile.
+#[derive(Default, Deserialize, Serialize)]
+pub struct UpdateUser {
+    pub name: Option<String>,
+    pub color: Option<String>,
+    pub bio: Option<String>,
+}
+
+/// This struct contains all the parameters needed to create a room.
+#[derive(Deserialize, Serialize)]
+pub struct CreateRoom {
+    pub name: String,
+}
+
+/// This struct contains all the parameters needed to create a message.
+#[derive(Deserialize, Serialize)]
+pub struct CreateMessage {
+    /// The room to post the message in. If `None`, it's posted in the default room,
+    /// or in the room of the thread for replies.
+    pub room: Option<Id>,
+    /// The message to reply to. If `None`, a new top-level message is created.
+    pub reply_to: Option<Id>,
+    pub text: String,
+}
+
+/// This struct contains all the parameters needed to update a message.
+#[derive(Deserialize, Serialize)]
+pub struct UpdateMessage {
+    pub message: Id,
+    pub text: String,
+}
+
+/// This struct contains all the parameters needed to react to a message.
+#[derive(Deserialize, Serialize)]
+pub struct CreateReaction {
+    pub emoji: String,
+}
+
+/// This struct contains all the parameters needed to delete a message.
+#[derive(Deserialize, Serialize)]
+pub struct DeleteMessage {
+    pub message: Id,
+}
+
+/// This constant is the ID of the default room, created along with the schema.
+pub const DEFAULT_ROOM: Id = 1;
+
+/// This constant is the number of messages per page when no limit is given.
+pub const DEFAULT_PAGE_LIMIT: i64 = 50;
+
+/// This constant is the maximum number of messages per page.
+pub const MAX_PAGE_LIMIT: i64 = 200;
+
+/// This constant is the maximum number of characters of a search query.
+pub const MAX_SEARCH_LENGTH: usize = 100;
+
+/// This constant is the number of seconds a session is valid for (30 days).
+pub const SESSION_LIFETIME: i64 = 30 * 24 * 60 * 60;
+
+impl Database {
+    /// This constructor initializes the store selected by the scheme of the
+    /// database URL, i.e. `postgres://...`, `sqlite://...` or `memory://`.
+    ///
+    /// Postgres and SQLite databases are migrated to the latest schema embedded in
+    /// the binary (see `migrations`), so it fails if the database is unreachable.
+    pub async fn new(config: &Config) -> Result<Self> {
+        let url = config.database_url.as_str();
+        let store: Box<dyn ChatStore> = match url.split(':').next() {
+            Some("postgres" | "postgresql") => {
+                Box::new(postgres::PostgresStore::new(config).await?)
+            }
+            Some("sqlite") => Box::new(sqlite::SqliteStore::new(config).await?),
+            Some("memory") => Box::new(memory::MemoryStore::new()),
+            _ => {
+                let error = format!("Unsupported database URL: {}", url);
+                return Err(DatabaseError::Storage(error));
+            }
+        };
+        let policy = match &config.moderation_policy {
+            Some(path) => ModerationPolicy::load(path, config.max_text_length)
+                .await
+                .map_err(DatabaseError::Validation)?,
+            None => ModerationPolicy::builtin(config.max_text_length),
+        };
+        Ok(Self {
+            store,
+            policy: RwLock::new(Arc::new(policy)),
+            policy_path: config.moderation_policy.clone(),
+            max_text_length: config.max_text_length,
+            restore_window: config.restore_window,
+            message_retention: config.message_retention,
+        })
+    }
+
+    /// This method creates a new user with a unique ID, a random password and the
+    /// input profile, if it's valid. It inserts the user in the database with the
+    /// password hashed, and returns their credentials. Otherwise, it returns an error.
+    pub async fn create_user(&self, params: CreateUser) -> Result<User> {
+        let name = params.name.trim().to_string();
+        Database::validate_user_name(&name)?;
+        let color = params.color.filter(|color| !color.is_empty());
+        let bio = params.bio.filter(|bio| !bio.is_empty());
+        Database::validate_profile(color.as_deref(), bio.as_deref())?;
+        let password = format!("{:x}", rand::random::<u128>());
+        let hash = Database::hash_password(password.clone()).await?;
+        let profile = NewProfile { name, color, bio };
+        let id = self.store.create_user(hash, profile).await?;
+        Ok(User { id, password })
+    }
+
+    /// This method returns the profile of the user with the input ID, if it exists.
+    pub async fn read_user(&self, id: Id) -> Result<Profile> {
+        self.store
+            .read_user(id)
+            .await?
+            .ok_or_else(|| DatabaseError::NotFound("User doesn't exist".to_string()))
+    }
+
+    /// This method returns the profiles of the users with the input IDs, skipping
+    /// those that don't exist.
+    pub async fn read_users(&self, ids: &[Id]) -> Result<Vec<Profile>> {
+        self.store.read_users(ids).await
+    }
+
+    /// This method updates the profile of the user with the input ID, if the
+    /// parameters are valid and the user is updating their own profile, and
+    /// returns it. Otherwise, it returns an error.
+    pub async fn update_user(&self, user: Id, id: Id, params: UpdateUser) -> Result<Profile> {
+        let profile = self.read_user(id).await?;
+        if user != id {
+            return Err(DatabaseError::Forbidden(
+                "You can only update your own profile!".to_string(),
+            ));
+        }
+        let name = match params.name {
+            Some(name) => name.trim().to_string(),
+            None => profile.name,
+        };
+        Database::validate_user_name(&name)?;
+        let color = match params.color {
+            Some(color) => Some(color).filter(|color| !color.is_empty()),
+            None => profile.color,
+        };
+        let bio = match params.bio {
+            Some(bio) => Some(bio).filter(|bio| !bio.is_empty()),
+            None => profile.bio,
+        };
+        Database::validate_profile(color.as_deref(), bio.as_deref())?;
+        let profile = Profile {
+            id,
+            name,
+            color,
+            bio,
+        };
+        self.store.update_user(profile).await
+    }
+
+    /// This method opens a new session for the input user, if their credentials
+    /// are valid, and returns it. Otherwise, it returns an error.
+    pub async fn create_session(&self, user: User) -> Result<Session> {
+        self.authenticate_user(&user).await?;
+        let token = format!(
+            "{:032x}{:032x}",
+            rand::random::<u128>(),
+            rand::random::<u128>()
+        );
+        let created = Database::generate_unix_timestamp()?;
+        let expires = created + SESSION_LIFETIME;
+        let hash = Database::hash_token(&token);
+        self.store
+            .create_session(hash, user.id, created, expires)
+            .await?;
+        Ok(Session {
+            token,
+            user: user.id,
+            expires,
+        })
+    }
+
+    /// This method returns the ID of the user who opened the session identified
+    /// by the input token, if it exists and hasn't expired.
+    pub async fn authenticate_token(&self, token: &str) -> Result<Id> {
+        let now = Database::generate_unix_timestamp()?;
+        self.store
+            .read_session(&Database::hash_token(token), now)
+            .await?
+            .ok_or_else(|| DatabaseError::Unauthorized("Session is invalid or expired".to_string()))
+    }
+
+    /// This method closes the session identified by the input token.
+    pub async fn delete_session(&self, token: &str) -> Result<()> {
+        self.store
+            .delete_session(&Database::hash_token(token))
+            .await
+    }
+
+    /// This method returns a page of messages sorted in chronological order,
+    /// using keyset pagination on `(created, id)`.
+    pub async fn read_messages(&self, params: ReadMessages) -> Result<MessagePage> {
+        let limit = Database::validate_limit(params.limit)?;
+        let (cursor, forward) = match (params.before, params.after) {
+            (Some(_), Some(_)) => {
+                let error = "Use either before or after, not both".to_string();
+                return Err(DatabaseError::Validation(error));
+            }
+            (None, Some(after)) => (Some(after), true),
+            (before, None) => (before, false),
+        };
+        if let Some(room) = params.room {
+            self.read_room(room).await?;
+        }
+        // Read one extra message to know whether there is a next page.
+        let query = PageQuery {
+            room: params.room,
+            cursor,
+            forward,
+            limit: limit + 1,
+        };
+        let mut messages = self.store.read_messages(query).await?;
+        let has_next_page = messages.len() as i64 > limit;
+        messages.truncate(limit as usize);
+        let next_cursor = messages.last().filter(|_| has_next_page).map(Cursor::from);
+        if !forward {
+            messages.reverse();
+        }
+        self.attach_reactions(&mut messages).await?;
+        let authors = messages.iter().map(|message| message.author);
+        let users = self.read_authors(authors).await?;
+        Ok(MessagePage {
+            messages,
+            users,
+            next_cursor,
+        })
+    }
+
+    /// This method returns the message with the input ID, if it exists.
+    pub async fn read_message(&self, id: Id) -> Result<Message> {
+        let mut message = self
+            .store
+            .read_message(id)
+            .await?
+            .ok_or_else(|| DatabaseError::NotFound("Message doesn't exist".to_string()))?;
+        self.attach_reactions(Some(&mut message)).await?;
+        Ok(message)
+    }
+
+    /// This method returns the best messages matching the full-text search, if the
+    /// parameters are valid. Otherwise, it returns an error.
+    pub async fn search_messages(&self, params: SearchMessages) -> Result<SearchResults> {
+        let text = params.q.trim().to_string();
+        if text.is_empty() {
+            return Err(DatabaseError::Validation(
+                "Search query can't be empty!".to_string(),
+            ));
+        }
+        if text.chars().count() > MAX_SEARCH_LENGTH {
+            return Err(DatabaseError::Validation(format!(
+                "Maximum {} characters per search please!",
+                MAX_SEARCH_LENGTH
+            )));
+        }
+        if let (Some(since), Some(until)) = (params.since, params.until) {
+            if since >= until {
+                return Err(DatabaseError::Validation(
+                    "The date range is empty!".to_string(),
+                ));
+            }
+        }
+        let limit = Database::validate_limit(params.limit)?;
+        if let Some(room) = params.room {
+            self.read_room(room).await?;
+        }
+        let query = SearchQuery {
+            text,
+            author: params.author,
+            room: params.room,
+            since: params.since,
+            until: params.until,
+            limit,
+        };
+        let mut results = self.store.search_messages(query).await?;
+        let messages = results.iter_mut().map(|result| &mut result.message);
+        self.attach_reactions(messages).await?;
+        let authors = results.iter().map(|result| result.message.author);
+        let users = self.read_authors(authors).await?;
+        Ok(SearchResults { results, users })
+    }
+
+    /// This method returns the former texts of the message in chronological order,
+    /// if it exists. Otherwise, it returns an error.
+    pub async fn read_revisions(&self, id: Id) -> Result<Vec<Revision>> {
+        self.read_undeleted_message(id).await?;
+        self.store.read_revisions(id).await
+    }
+
+    /// This method returns the thread of the message with the input ID, i.e. the
+    /// message and its replies, or the thread it belongs to if it's a reply.
+    pub async fn read_thread(&self, id: Id) -> Result<Thread> {
+        let mut message = self.read_message(id).await?;
+        if let Some(parent) = message.parent {
+            message = self.read_message(parent).await?;
+        }
+        let mut replies = self.store.read_replies(message.id).await?;
+        self.attach_reactions(&mut replies).await?;
+        let authors = std::iter::once(&message).chain(&replies);
+        let users = self
+            .read_authors(authors.map(|message| message.author))
+            .await?;
+        Ok(Thread {
+            message,
+            replies,
+            users,
+        })
+    }
+
+    /// This method returns all rooms sorted by name.
+    pub async fn read_rooms(&self) -> Result<Vec<Room>> {
+        self.store.read_rooms().await
+    }
+
+    /// This method returns the room with the input ID, if it exists.
+    pub async fn read_room(&self, id: Id) -> Result<Room> {
+        self.store
+            .read_room(id)
+            .await?
+            .ok_or_else(|| DatabaseError::NotFound("Room doesn't exist".to_string()))
+    }
+
+    /// This method creates a new room in the database, if the parameters
+    /// are valid, and returns it. Otherwise, it returns an error.
+    pub async fn create_room(&self, params: CreateRoom) -> Result<Room> {
+        let name = params.name.trim().to_string();
+        Database::validate_room_name(&name)?;
+        let created = Database::generate_unix_timestamp()?;
+        self.store.create_room(name, created).await
+    }
+
+    /// This method creates a new message in the database, if the parameters
+    /// are valid, and returns it. Otherwise, it returns an error.
+    pub async fn create_message(&self, author: Id, params: CreateMessage) -> Result<Message> {
+        let (room, parent) = match params.reply_to {
+            Some(reply_to) => {
+                let replied = self.read_undeleted_message(reply_to).await?;
+                if matches!(params.room, Some(room) if room != replied.room) {
+                    return Err(DatabaseError::Validation(
+                        "Replies must be posted in the room of their thread!".to_string(),
+                    ));
+                }
+                (replied.room, Some(replied.parent.unwrap_or(replied.id)))
+            }
+            None => {
+                let room = self.read_room(params.room.unwrap_or(DEFAULT_ROOM)).await?;
+                (room.id, None)
+            }
+        };
+        let moderated = self.moderate_text(&params.text)?;
+        let created = Database::generate_unix_timestamp()?;
+        let message = NewMessage {
+            author,
+            room,
+            parent,
+            text: moderated.text,
+            flagged: moderated.flagged,
+            created,
+        };
+        self.store.create_message(message).await
+    }
+
+    /// This method updates an existing message in the database, if the parameters
+    /// are valid, and returns it. Otherwise, it returns an error.
+    pub async fn update_message(&self, user: Id, params: UpdateMessage) -> Result<Message> {
+        self.validate_authorship(user, params.message).await?;
+        let moderated = self.moderate_text(&params.text)?;
+        let modified = Database::generate_unix_timestamp()?;
+        let mut message = self
+            .store
+            .update_message(params.message, moderated, modified)
+            .await?;
+        self.attach_reactions(Some(&mut message)).await?;
+        Ok(message)
+    }
+
+    /// This method deletes an existing message in the database, if the parameters
+    /// are valid, and returns it. Otherwise, it returns an error.
+    pub async fn delete_message(&self, user: Id, params: DeleteMessage) -> Result<Message> {
+        self.validate_authorship(user, params.message).await?;
+        let deleted = Database::generate_unix_timestamp()?;
+        self.store.delete_message(params.message, deleted).await
+    }
+
+    /// This method restores a message deleted by the user within the restore window,
+    /// and returns it. Otherwise, it returns an error.
+    pub async fn restore_message(&self, user: Id, id: Id) -> Result<Message> {
+        let message = self.read_message(id).await?;
+        if message.author != user {
+            return Err(DatabaseError::Forbidden(
+                "You're not the author!".to_string(),
+            ));
+        }
+        if !message.deleted {
+            return Err(DatabaseError::Validation(
+                "Message isn't deleted!".to_string(),
+            ));
+        }
+        let deleted_since = Database::generate_unix_timestamp()? - self.restore_window;
+        let mut message = self
+            .store
+            .restore_message(id, deleted_since)
+            .await?
+            .ok_or_else(|| {
+                DatabaseError::Validation("Too late to restore the message!".to_string())
+            })?;
+        self.attach_reactions(Some(&mut message)).await?;
+        Ok(message)
+    }
+
+    /// This method reloads the moderation policy from its file, and returns its
+    /// settings. Messages posted before aren't moderated again.
+    pub async fn reload_policy(&self) -> Result<PolicyConfig> {
+        let path = self.policy_path.as_ref().ok_or_else(|| {
+            DatabaseError::Validation("No moderation policy file is configured".to_string())
+        })?;
+        let policy = ModerationPolicy::load(path, self.max_text_length)
+            .await
+            .map_err(DatabaseError::Validation)?;
+        let config = policy.config().clone();
+        *self.policy.write() = Arc::new(policy);
+        tracing::info!("moderation policy reloaded from {}", path.display());
+        Ok(config)
+    }
+
+    /// This method permanently deletes the messages deleted for longer than the
+    /// retention period, and returns how many were purged. Deleted messages with
+    /// replies are kept as tombstones until their replies are purged too.
+    pub async fn purge_messages(&self) -> Result<u64> {
+        let deleted_before = Database::generate_unix_timestamp()? - self.message_retention;
+        self.store.purge_messages(deleted_before).await
+    }
+
+    /// This method appends the event of the room, serialized by the caller, to the
+    /// event log, and returns its sequence number.
+    pub async fn create_event(&self, room: Id, payload: String) -> Result<Seq> {
+        let created = Database::generate_unix_timestamp()?;
+        self.store.create_event(room, payload, created).await
+    }
+
+    /// This method returns up to `limit` events logged after `since`, in the input
+    /// rooms or in all rooms if none is given, sorted by sequence number. It returns
+    /// `None` if some of these events have already been purged, including when the
+    /// event log is empty because all of them have been, or if `since` is ahead of
+    /// the event log, e.g. because the database was reset.
+    pub async fn read_events(
+        &self,
+        since: Seq,
+        rooms: &[Id],
+        limit: i64,
+    ) -> Result<Option<Vec<LoggedEvent>>> {
+        let events = self.store.read_events(since, rooms, limit).await?;
+        // Read the oldest event afterwards, so that it can't be purged in between.
+        let oldest = self.store.read_oldest_event().await?;
+        let last = self.store.read_last_seq().await?;
+        let replayable = match oldest {
+            _ if since > last => false,
+            Some(oldest) => oldest <= since.saturating_add(1),
+            None => since == last,
+        };
+        Ok(if replayable { Some(events) } else { None })
+    }
+
+    /// This method returns the sequence number of the last logged event, even if it
+    /// has been purged since, or 0 if no event has ever been logged.
+    pub async fn read_last_seq(&self) -> Result<Seq> {
+        self.store.read_last_seq().await
+    }
+
+    /// This method permanently deletes the events logged for longer than the
+    /// retention period of deleted messages, and returns how many were purged.
+    pub async fn purge_events(&self) -> Result<u64> {
+        let created_before = Database::generate_unix_timestamp()? - self.message_retention;
+        self.store.purge_events(created_before).await
+    }
+
+    /// This method adds the reaction of the user to an existing message, if the
+    /// emoji is valid, and returns the message. Reacting twice with the same emoji
+    /// has no effect. Otherwise, it returns an error.
+    pub async fn create_reaction(
+        &self,
+        user: Id,
+        message: Id,
+        params: CreateReaction,
+    ) -> Result<Message> {
+        self.read_undeleted_message(message).await?;
+        Database::validate_emoji(&params.emoji)?;
+        let created = Database::generate_unix_timestamp()?;
+        self.store
+            .create_reaction(message, user, params.emoji, created)
+            .await?;
+        self.read_message(message).await
+    }
+
+    /// This method removes the reaction of the user to an existing message, if it
+    /// exists, and returns the message. Otherwise, it returns an error.
+    pub async fn delete_reaction(&self, user: Id, message: Id, emoji: &str) -> Result<Message> {
+        self.read_message(message).await?;
+        if !self.store.delete_reaction(message, user, emoji).await? {
+            return Err(DatabaseError::NotFound(
+                "Reaction doesn't exist".to_string(),
+            ));
+        }
+        self.read_message(message).await
+    }
+
+    /// This private method returns the message with the input ID, if it exists and
+    /// isn't deleted. Otherwise, it returns an error.
+    async fn read_undeleted_message(&self, id: Id) -> Result<Message> {
+        let message = self.read_message(id).await?;
+        if message.deleted {
+            return Err(DatabaseError::NotFound("Message doesn't exist".to_string()));
+        }
+        Ok(message)
+    }
+
+    /// This private method fills in the aggregated reactions of the input messages,
+    /// except tombstones.
+    async fn attach_reactions<'a>(
+        &self,
+        messages: impl IntoIterator<Item = &'a mut Message>,
+    ) -> Result<()> {
+        let messages = messages.into_iter().filter(|message| !message.deleted);
+        let mut messages: Vec<&mut Message> = messages.collect();
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let ids: Vec<Id> = messages.iter().map(|message| message.id).collect();
+        for reaction in self.store.read_reactions(&ids).await? {
+            let message = messages.iter_mut().find(|m| m.id == reaction.message);
+            if let Some(message) = message {
+                message.reactions.push(Reaction {
+                    emoji: reaction.emoji,
+                    count: reaction.count,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// This private method returns the profiles of the input authors, once each.
+    async fn read_authors(&self, authors: impl Iterator<Item = Id>) -> Result<Vec<Profile>> {
+        let mut authors: Vec<Id> = authors.collect();
+        authors.sort_unstable();
+        authors.dedup();
+        self.store.read_users(&authors).await
+    }
+
+    /// This private function generates a timestamp based on the current system time.
+    fn generate_unix_timestamp() -> Result<Timestamp> {
+        match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(duration) => Ok(duration.as_secs() as i64),
+            Err(_) => Err(DatabaseError::Storage(
+                "Failed to retrieve server time".to_string(),
+            )),
+        }
+    }
+
+    /// This private function hashes a password with argon2 and a random salt.
+    ///
+    /// Hashing is deliberately slow, so it runs on a blocking thread.
+    async fn hash_password(password: String) -> Result<String> {
+        let hash = tokio::task::spawn_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            let hash = Argon2::default().hash_password(password.as_bytes(), &salt);
+            hash.map(|hash| hash.to_string())
+        });
+        match hash.await {
+            Ok(Ok(hash)) => Ok(hash),
+            _ => Err(DatabaseError::Storage(
+                "Failed to hash password".to_string(),
+            )),
+        }
+    }
+
+    /// This private function compares a plaintext password in constant time, so that
+    /// it can't be guessed from how long the comparison takes.
+    fn plaintext_matches(stored: &str, password: &str) -> bool {
+        let diff = stored
+            .bytes()
+            .zip(password.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        diff == 0 && stored.len() == password.len()
+    }
+
+    /// This private function hashes a session token, which is already random
+    /// enough that a fast unsalted hash is sufficient.
+    fn hash_token(token: &str) -> String {
+        format!("{:x}", Sha256::digest(token.as_bytes()))
+    }
+
+    /// This private method validates that the input user ID exists and that their
+    /// password matches the hash of the corresponding user in the database.
+    ///
+    /// Users created with the former `postgres/init.sql` schema have plaintext
+    /// passwords, which are replaced by their hash on the first successful login.
+    async fn authenticate_user(&self, user: &User) -> Result<()> {
+        let hash = self
+            .store
+            .read_password(user.id)
+            .await?
+            .ok_or_else(|| DatabaseError::Unauthorized("Username doesn't exist".to_string()))?;
+        let password = user.password.clone();
+        let verified = tokio::task::spawn_blocking(move || match PasswordHash::new(&hash) {
+            Ok(hash) => {
+                let verified = Argon2::default().verify_password(password.as_bytes(), &hash);
+                (verified.is_ok(), false)
+            }
+            Err(_) => (Self::plaintext_matches(&hash, &password), true),
+        });
+        match verified.await {
+            Ok((true, false)) => Ok(()),
+            Ok((true, true)) => {
+                let hash = Self::hash_password(user.password.clone()).await?;
+                self.store.update_password(user.id, hash).await?;
+                tracing::info!("hashed the plaintext password of user {}", user.id);
+                Ok(())
+            }
+            Ok((false, _)) => Err(DatabaseError::Unauthorized(
+                "Password doesn't match".to_string(),
+            )),
+            Err(_) => Err(DatabaseError::Storage(
+                "Failed to verify password".to_string(),
+            )),
+        }
+    }
+
+    /// This private method validates that the input message ID exists and that the
+    /// user matches the author of the corresponding message in the database.
+    async fn validate_authorship(&self, user: Id, id: Id) -> Result<()> {
+        let matched_message = self.read_undeleted_message(id).await?;
+        if matched_message.author != user {
+            return Err(DatabaseError::Forbidden(
+                "You're not the author!".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// This private function validates the display name of a user.
+    fn validate_user_name(name: &str) -> Result<()> {
+        if name.is_empty() {
+            return Err(DatabaseError::Validation(
+                "Name can't be empty!".to_string(),
+            ));
+        }
+        if name.chars().count() > 30 {
+            return Err(DatabaseError::Validation(
+                "Maximum 30 characters per name please!".to_string(),
+            ));
+        }
+        if !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == ' ' || c == '-' || c == '_' || c == '.')
+        {
+            return Err(DatabaseError::Validation(
+                "Letters, digits, spaces, dashes, underscores and dots only please!".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// This private function validates the optional fields of a user profile.
+    fn validate_profile(color: Option<&str>, bio: Option<&str>) -> Result<()> {
+        if let Some(color) = color {
+            let hex = color.strip_prefix('#').unwrap_or_default();
+            if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(DatabaseError::Validation(
+                    "Colors must be formatted as #rrggbb!".to_string(),
+                ));
+            }
+        }
+        if let Some(bio) = bio {
+            if bio.chars().count() > 200 {
+                return Err(DatabaseError::Validation(
+                    "Maximum 200 characters per bio please!".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// This private function validates the name of a new room.
+    fn validate_room_name(name: &str) -> Result<()> {
+        if name.is_empty() {
+            return Err(DatabaseError::Validation(
+                "Room name can't be empty!".to_string(),
+            ));
+        }
+        if name.chars().count() > 50 {
+            return Err(DatabaseError::Validation(
+                "Maximum 50 characters please!".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// This private function validates the maximum number of messages to read.
+    fn validate_limit(limit: Option<i64>) -> Result<i64> {
+        let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+        if !(1..=MAX_PAGE_LIMIT).contains(&limit) {
+            let error = format!("Limit must be between 1 and {}", MAX_PAGE_LIMIT);
+            return Err(DatabaseError::Validation(error));
+        }
+        Ok(limit)
+    }
+
+    /// This private function validates the emoji of a reaction, which may be made of
+    /// several characters (e.g. with skin tones or joiners) but never ASCII ones.
+    fn validate_emoji(emoji: &str) -> Result<()> {
+        let count = emoji.chars().count();
+        if count == 0 || count > 10 || emoji.chars().any(|c| c.is_ascii() || c.is_whitespace()) {
+            return Err(DatabaseError::Validation(
+                "Reactions must be a single emoji!".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// This private method applies the moderation policy to the user input text, and
+    /// returns the moderated text if it's accepted. Otherwise, it returns an error.
+    fn moderate_text(&self, text: &str) -> Result<Moderated> {
+        let policy = self.policy.read().clone();
+        policy.moderate(text).map_err(DatabaseError::Validation)
+    }
+}
@@ -0,0 +1,301 @@
+//! This module implements the moderation policy applied to the text of messages.
+//!
+//! A policy is loaded from a TOML file, e.g.
+//!
+//! ```toml
+//! max_length = 500
+//! unicode = true
+//!
+//! [[rules]]
+//! name = "swear words"
+//! words = ["fuck", "shit"]
+//! leet = true
+//! action = "mask"
+//!
+//! [[rules]]
+//! name = "links"
+//! pattern = "https?://"
+//! action = "flag"
+//! ```
+//!
+//! Texts are first normalized to NFC, so that e.g. `é` is stored the same way
+//! whether it was typed as one character or as `e` and a combining accent. Control
+//! and bidirectional override characters are always rejected, and lengths count
+//! grapheme clusters, i.e. what users perceive as characters, e.g. `👍🏽` counts as 1.
+//!
+//! Rules are applied in order: the first rejecting rule fails the whole text, masking
+//! rules replace the matched characters with asterisks, and flagging rules mark the
+//! message for review by a moderator.
+
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// This constant is the maximum number of code points per grapheme cluster, which is
+/// plenty for emoji sequences but stops combining marks from piling up.
+const MAX_GRAPHEME_LENGTH: usize = 32;
+
+/// This struct holds the settings of a moderation policy, as written in its file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// The maximum number of grapheme clusters per message. If `None`, the maximum
+    /// text length of the runtime configuration applies.
+    pub max_length: Option<usize>,
+    /// Whether non-ASCII characters are allowed.
+    #[serde(default = "default_true")]
+    pub unicode: bool,
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+/// This struct holds the settings of a rule of a moderation policy. Each rule
+/// matches either a list of words or a regular expression, regardless of case.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub name: String,
+    #[serde(default)]
+    pub words: Vec<String>,
+    /// Whether words also match their leet-speak spelling, e.g. `sh1t` or `$hit`.
+    #[serde(default)]
+    pub leet: bool,
+    /// Whether words only match whole words, e.g. `ass` but not `class`.
+    #[serde(default = "default_true")]
+    pub whole_word: bool,
+    pub pattern: Option<String>,
+    pub action: Action,
+    /// The error returned when the rule rejects a message.
+    pub message: Option<String>,
+}
+
+/// This enum lists what a rule does to the messages it matches.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// The message is rejected with a validation error.
+    Reject,
+    /// The matched characters are replaced with asterisks.
+    Mask,
+    /// The message is accepted, but flagged for review.
+    Flag,
+}
+
+/// This struct represents a moderation policy ready to be applied.
+pub struct ModerationPolicy {
+    config: PolicyConfig,
+    max_length: usize,
+    rules: Vec<Rule>,
+}
+
+/// This struct represents a rule of a [`ModerationPolicy`], with its words or
+/// pattern compiled into a single regular expression.
+struct Rule {
+    name: String,
+    regex: Regex,
+    whole_word: bool,
+    action: Action,
+    message: Option<String>,
+}
+
+/// This struct represents a text accepted by a [`ModerationPolicy`].
+pub struct Moderated {
+    /// The text with the masked characters replaced with asterisks.
+    pub text: String,
+    /// Whether a rule flagged the text for review.
+    pub flagged: bool,
+}
+
+impl ModerationPolicy {
+    /// This constructor compiles the policy of the input settings, or returns an
+    /// error if a rule is invalid.
+    pub fn new(config: PolicyConfig, default_max_length: usize) -> Result<Self, String> {
+        let rules = config
+            .rules
+            .iter()
+            .map(Rule::new)
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            max_length: config.max_length.unwrap_or(default_max_length),
+            config,
+            rules,
+        })
+    }
+
+    /// This constructor returns the built-in policy used without a policy file: no
+    /// swear words.
+    pub fn builtin(max_length: usize) -> Self {
+        let swear_words = RuleConfig {
+            name: "swear words".to_string(),
+            words: vec!["fuck".to_string()],
+            leet: false,
+            whole_word: false,
+            pattern: None,
+            action: Action::Reject,
+            message: Some("No swear words please!".to_string()),
+        };
+        let config = PolicyConfig {
+            max_length: None,
+            unicode: true,
+            rules: vec![swear_words],
+        };
+        // Safe unwrap: the built-in rule is valid.
+        Self::new(config, max_length).unwrap()
+    }
+
+    /// This constructor loads and compiles the policy of the input file.
+    pub async fn load(path: &Path, default_max_length: usize) -> Result<Self, String> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let config = toml::from_str(&content)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+        Self::new(config, default_max_length)
+    }
+
+    /// This method returns the settings of the policy.
+    pub fn config(&self) -> &PolicyConfig {
+        &self.config
+    }
+
+    /// This method applies the policy to the NFC normalization of the text, and
+    /// returns the moderated text or the error explaining why it's rejected.
+    pub fn moderate(&self, text: &str) -> Result<Moderated, String> {
+        let text: String = text.nfc().collect();
+        if text.chars().any(is_forbidden) {
+            return Err("No control or bidirectional override characters please!".to_string());
+        }
+        let graphemes: Vec<&str> = text.graphemes(true).collect();
+        if graphemes.len() > self.max_length {
+            return Err(format!("Maximum {} characters please!", self.max_length));
+        }
+        if graphemes
+            .iter()
+            .any(|grapheme| grapheme.chars().count() > MAX_GRAPHEME_LENGTH)
+        {
+            return Err("Too many combining marks please!".to_string());
+        }
+        if !self.config.unicode && !text.is_ascii() {
+            return Err("ASCII characters only please!".to_string());
+        }
+        let mut moderated = Moderated {
+            text,
+            flagged: false,
+        };
+        for rule in &self.rules {
+            let matches = rule.find(&moderated.text);
+            if matches.is_empty() {
+                continue;
+            }
+            match rule.action {
+                Action::Reject => {
+                    let error = rule.message.clone().unwrap_or_else(|| {
+                        format!("Your message breaks the rule \"{}\"!", rule.name)
+                    });
+                    return Err(error);
+                }
+                Action::Mask => moderated.text = mask(&moderated.text, &matches),
+                Action::Flag => {
+                    tracing::info!("text flagged by moderation rule \"{}\"", rule.name);
+                    moderated.flagged = true;
+                }
+            }
+        }
+        Ok(moderated)
+    }
+}
+
+impl Rule {
+    /// This constructor compiles the words or pattern of the rule.
+    fn new(config: &RuleConfig) -> Result<Self, String> {
+        let pattern = match (&config.pattern, config.words.is_empty()) {
+            (Some(pattern), true) => pattern.clone(),
+            (None, false) => {
+                // Match the longest words first, e.g. `asshole` before `ass`.
+                let mut words: Vec<&String> = config.words.iter().collect();
+                words.sort_by_key(|word| std::cmp::Reverse(word.chars().count()));
+                let words = words.iter().map(|word| word_pattern(word, config.leet));
+                words.collect::<Vec<_>>().join("|")
+            }
+            _ => {
+                let error = "needs either words or a pattern";
+                return Err(format!("Rule \"{}\" {}", config.name, error));
+            }
+        };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| format!("Rule \"{}\" is invalid: {}", config.name, e))?;
+        Ok(Self {
+            name: config.name.clone(),
+            regex,
+            whole_word: config.whole_word && config.pattern.is_none(),
+            action: config.action,
+            message: config.message.clone(),
+        })
+    }
+
+    /// This method returns the byte ranges of the text matched by the rule.
+    fn find(&self, text: &str) -> Vec<(usize, usize)> {
+        let is_word = |c: Option<char>| matches!(c, Some(c) if c.is_alphanumeric());
+        self.regex
+            .find_iter(text)
+            .filter(|m| {
+                !self.whole_word
+                    || !(is_word(text[..m.start()].chars().next_back())
+                        || is_word(text[m.end()..].chars().next()))
+            })
+            .map(|m| (m.start(), m.end()))
+            .collect()
+    }
+}
+
+/// This function returns the regular expression matching the word, including its
+/// leet-speak spelling if requested.
+fn word_pattern(word: &str, leet: bool) -> String {
+    word.chars()
+        .map(|c| {
+            let variants = match c.to_ascii_lowercase() {
+                'a' if leet => "a4@",
+                'b' if leet => "b8",
+                'e' if leet => "e3",
+                'g' if leet => "g9",
+                'i' if leet => "i1!|",
+                'l' if leet => "l1|",
+                'o' if leet => "o0",
+                's' if leet => "s5$",
+                't' if leet => "t7+",
+                _ => return regex::escape(&c.to_string()),
+            };
+            format!("[{}]", regex::escape(variants))
+        })
+        .collect()
+}
+
+/// This function replaces the characters in the byte ranges of the text with asterisks.
+fn mask(text: &str, ranges: &[(usize, usize)]) -> String {
+    let mut masked = String::with_capacity(text.len());
+    let mut end = 0;
+    for &(start, stop) in ranges {
+        masked.push_str(&text[end..start]);
+        masked.push_str(&"*".repeat(text[start..stop].chars().count()));
+        end = stop;
+    }
+    masked.push_str(&text[end..]);
+    masked
+}
+
+/// This function returns whether the character is forbidden in all texts, i.e. is
+/// a control character other than a newline or tab, or a bidirectional override
+/// that could make a text display differently from what it says.
+fn is_forbidden(c: char) -> bool {
+    (c.is_control() && c != '\n' && c != '\t')
+        || matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// This function returns `true`, as the default of boolean settings.
+fn default_true() -> bool {
+    true
+}
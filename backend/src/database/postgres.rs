@@ -0,0 +1,516 @@
+//! This module implements the [`ChatStore`] on top of postgres.
+
+use super::moderation::Moderated;
+use super::{ChatStore, DatabaseError, Id, Message, NewProfile, PageQuery, Profile, Result};
+use super::{LoggedEvent, Seq, Timestamp};
+use super::{MessageRow, NewMessage, ReactionCount, Revision, Room, SearchQuery, SearchResult};
+use crate::config::Config;
+use async_trait::async_trait;
+use sqlx::migrate::{MigrateError, Migrator};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{FromRow, PgPool, Row};
+use std::time::Duration;
+
+/// This constant is the postgres error code of a unique constraint violation.
+const UNIQUE_VIOLATION: &str = "23505";
+
+/// This constant is the number of attempts to reach postgres at startup, one
+/// second apart, since it may still be starting up alongside the backend.
+const CONNECT_ATTEMPTS: u32 = 10;
+
+/// This static holds the postgres schema migrations embedded in the binary.
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// This struct stores all documents in postgres, using the schema in `migrations/postgres`.
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    /// This constructor initializes the connection pool for postgres and applies
+    /// any pending migration, waiting for postgres to accept connections.
+    pub async fn new(config: &Config) -> Result<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(config.pool_size)
+            .connect_lazy(&config.database_url)
+            .map_err(|e| storage_error("Invalid database URL", e))?;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match MIGRATOR.run(&pool).await {
+                Ok(()) => break,
+                Err(MigrateError::Execute(
+                    error @ (sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut),
+                )) if attempts < CONNECT_ATTEMPTS => {
+                    tracing::warn!("waiting for postgres: {}", error);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                Err(error) => {
+                    let error = format!("Failed to migrate database: {}", error);
+                    return Err(DatabaseError::Storage(error));
+                }
+            }
+        }
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl ChatStore for PostgresStore {
+    async fn create_user(&self, password: String, profile: NewProfile) -> Result<Id> {
+        sqlx::query_scalar(
+            "INSERT INTO users(password, name, color, bio) VALUES ($1, $2, $3, $4) RETURNING id",
+        )
+        .bind(password)
+        .bind(profile.name)
+        .bind(profile.color)
+        .bind(profile.bio)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| unique_error("Name is already taken", "Failed to create user", e))
+    }
+
+    async fn read_password(&self, id: Id) -> Result<Option<String>> {
+        sqlx::query_scalar("SELECT password FROM users WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read user", e))
+    }
+
+    async fn update_password(&self, id: Id, password: String) -> Result<()> {
+        sqlx::query("UPDATE users SET password = $1 WHERE id = $2")
+            .bind(password)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to update password", e))?;
+        Ok(())
+    }
+
+    async fn read_user(&self, id: Id) -> Result<Option<Profile>> {
+        sqlx::query_as("SELECT id, name, color, bio FROM users WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read user", e))
+    }
+
+    async fn read_users(&self, ids: &[Id]) -> Result<Vec<Profile>> {
+        sqlx::query_as("SELECT id, name, color, bio FROM users WHERE id = ANY($1)")
+            .bind(ids)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read users", e))
+    }
+
+    async fn update_user(&self, profile: Profile) -> Result<Profile> {
+        sqlx::query_as(
+            "UPDATE users SET name = $1, color = $2, bio = $3 WHERE id = $4 \
+             RETURNING id, name, color, bio",
+        )
+        .bind(profile.name)
+        .bind(profile.color)
+        .bind(profile.bio)
+        .bind(profile.id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| unique_error("Name is already taken", "Failed to update user", e))
+    }
+
+    async fn create_session(
+        &self,
+        token: String,
+        user: Id,
+        created: Timestamp,
+        expires: Timestamp,
+    ) -> Result<()> {
+        sqlx::query("INSERT INTO sessions(token, owner, created, expires) VALUES ($1, $2, $3, $4)")
+            .bind(token)
+            .bind(user)
+            .bind(created)
+            .bind(expires)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to create session", e))?;
+        Ok(())
+    }
+
+    async fn read_session(&self, token: &str, now: Timestamp) -> Result<Option<Id>> {
+        sqlx::query_scalar("SELECT owner FROM sessions WHERE token = $1 AND expires > $2")
+            .bind(token)
+            .bind(now)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read session", e))
+    }
+
+    async fn delete_session(&self, token: &str) -> Result<()> {
+        sqlx::query("DELETE FROM sessions WHERE token = $1")
+            .bind(token)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to delete session", e))?;
+        Ok(())
+    }
+
+    async fn read_rooms(&self) -> Result<Vec<Room>> {
+        sqlx::query_as("SELECT * FROM rooms ORDER BY name ASC")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read rooms", e))
+    }
+
+    async fn read_room(&self, id: Id) -> Result<Option<Room>> {
+        sqlx::query_as("SELECT * FROM rooms WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read room", e))
+    }
+
+    async fn create_room(&self, name: String, created: Timestamp) -> Result<Room> {
+        sqlx::query_as("INSERT INTO rooms(name, created) VALUES ($1, $2) RETURNING *")
+            .bind(name)
+            .bind(created)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| unique_error("Room already exists", "Failed to create room", e))
+    }
+
+    async fn read_messages(&self, query: PageQuery) -> Result<Vec<Message>> {
+        let sql = if query.forward {
+            "SELECT * FROM messages \
+             WHERE parent IS NULL AND ($4::INT4 IS NULL OR room = $4) \
+             AND (created, id) > ($1, $2) \
+             ORDER BY created ASC, id ASC LIMIT $3"
+        } else {
+            "SELECT * FROM messages \
+             WHERE parent IS NULL AND ($4::INT4 IS NULL OR room = $4) \
+             AND ($1::INT8 IS NULL OR (created, id) < ($1, $2)) \
+             ORDER BY created DESC, id DESC LIMIT $3"
+        };
+        let rows: Vec<MessageRow> = sqlx::query_as(sql)
+            .bind(query.cursor.map(|cursor| cursor.created))
+            .bind(query.cursor.map(|cursor| cursor.id))
+            .bind(query.limit)
+            .bind(query.room)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read messages", e))?;
+        Ok(rows.into_iter().map(Message::from).collect())
+    }
+
+    async fn read_message(&self, id: Id) -> Result<Option<Message>> {
+        let row: Option<MessageRow> = sqlx::query_as("SELECT * FROM messages WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read message", e))?;
+        Ok(row.map(Message::from))
+    }
+
+    async fn read_revisions(&self, id: Id) -> Result<Vec<Revision>> {
+        sqlx::query_as("SELECT * FROM message_revisions WHERE message = $1 ORDER BY id ASC")
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read revisions", e))
+    }
+
+    async fn search_messages(&self, query: SearchQuery) -> Result<Vec<SearchResult>> {
+        // The text is escaped for HTML before highlighting, so that the snippet
+        // only contains the `<mark>` tags added by postgres.
+        let rows = sqlx::query(
+            "SELECT m.*, ts_rank(to_tsvector('english', text), query) AS rank, \
+             ts_headline('english', \
+             replace(replace(replace(text, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), \
+             query, 'StartSel=<mark>, StopSel=</mark>') AS snippet \
+             FROM messages m, websearch_to_tsquery('english', $1) query \
+             WHERE to_tsvector('english', text) @@ query AND deleted_at IS NULL \
+             AND ($2::INT4 IS NULL OR author = $2) \
+             AND ($3::INT4 IS NULL OR room = $3) \
+             AND ($4::INT8 IS NULL OR created >= $4) \
+             AND ($5::INT8 IS NULL OR created < $5) \
+             ORDER BY rank DESC, created DESC, id DESC LIMIT $6",
+        )
+        .bind(query.text)
+        .bind(query.author)
+        .bind(query.room)
+        .bind(query.since)
+        .bind(query.until)
+        .bind(query.limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| storage_error("Failed to search messages", e))?;
+        rows.iter()
+            .map(|row| {
+                Ok(SearchResult {
+                    message: MessageRow::from_row(row)?.into(),
+                    rank: row.try_get("rank")?,
+                    snippet: row.try_get("snippet")?,
+                })
+            })
+            .collect::<sqlx::Result<_>>()
+            .map_err(|e| storage_error("Failed to search messages", e))
+    }
+
+    async fn read_replies(&self, id: Id) -> Result<Vec<Message>> {
+        let rows: Vec<MessageRow> =
+            sqlx::query_as("SELECT * FROM messages WHERE parent = $1 ORDER BY created ASC, id ASC")
+                .bind(id)
+                .fetch_all(&self.pool)
+                .await
+                .map_err(|e| storage_error("Failed to read replies", e))?;
+        Ok(rows.into_iter().map(Message::from).collect())
+    }
+
+    async fn create_message(&self, message: NewMessage) -> Result<Message> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| storage_error("Failed to create message", e))?;
+        let created_message: MessageRow = sqlx::query_as(
+            "INSERT INTO messages(author, room, parent, text, created, flagged) \
+             VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+        )
+        .bind(message.author)
+        .bind(message.room)
+        .bind(message.parent)
+        .bind(message.text)
+        .bind(message.created)
+        .bind(message.flagged)
+        .fetch_one(&mut tx)
+        .await
+        .map_err(|e| storage_error("Failed to create message", e))?;
+        if let Some(parent) = message.parent {
+            sqlx::query("UPDATE messages SET reply_count = reply_count + 1 WHERE id = $1")
+                .bind(parent)
+                .execute(&mut tx)
+                .await
+                .map_err(|e| storage_error("Failed to update thread", e))?;
+        }
+        tx.commit()
+            .await
+            .map_err(|e| storage_error("Failed to create message", e))?;
+        Ok(created_message.into())
+    }
+
+    async fn update_message(
+        &self,
+        id: Id,
+        text: Moderated,
+        modified: Timestamp,
+    ) -> Result<Message> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| storage_error("Failed to update message", e))?;
+        sqlx::query(
+            "INSERT INTO message_revisions(message, text, created, replaced) \
+             SELECT id, text, COALESCE(modified, created), $2 FROM messages WHERE id = $1",
+        )
+        .bind(id)
+        .bind(modified)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| storage_error("Failed to save revision", e))?;
+        let updated_message: MessageRow = sqlx::query_as(
+            "UPDATE messages SET text = $1, modified = $2, edit_count = edit_count + 1, \
+             flagged = flagged OR $4 WHERE id = $3 RETURNING *",
+        )
+        .bind(text.text)
+        .bind(modified)
+        .bind(id)
+        .bind(text.flagged)
+        .fetch_one(&mut tx)
+        .await
+        .map_err(|e| storage_error("Failed to update message", e))?;
+        tx.commit()
+            .await
+            .map_err(|e| storage_error("Failed to update message", e))?;
+        Ok(updated_message.into())
+    }
+
+    async fn delete_message(&self, id: Id, deleted: Timestamp) -> Result<Message> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| storage_error("Failed to delete message", e))?;
+        let deleted_message: MessageRow = sqlx::query_as(
+            "UPDATE messages SET deleted_at = $2 \
+             WHERE id = $1 AND deleted_at IS NULL RETURNING *",
+        )
+        .bind(id)
+        .bind(deleted)
+        .fetch_one(&mut tx)
+        .await
+        .map_err(|e| storage_error("Failed to delete message", e))?;
+        if let Some(parent) = deleted_message.parent {
+            sqlx::query("UPDATE messages SET reply_count = reply_count - 1 WHERE id = $1")
+                .bind(parent)
+                .execute(&mut tx)
+                .await
+                .map_err(|e| storage_error("Failed to update thread", e))?;
+        }
+        tx.commit()
+            .await
+            .map_err(|e| storage_error("Failed to delete message", e))?;
+        Ok(deleted_message.into())
+    }
+
+    async fn restore_message(&self, id: Id, deleted_since: Timestamp) -> Result<Option<Message>> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| storage_error("Failed to restore message", e))?;
+        let restored_message: Option<MessageRow> = sqlx::query_as(
+            "UPDATE messages SET deleted_at = NULL \
+             WHERE id = $1 AND deleted_at >= $2 RETURNING *",
+        )
+        .bind(id)
+        .bind(deleted_since)
+        .fetch_optional(&mut tx)
+        .await
+        .map_err(|e| storage_error("Failed to restore message", e))?;
+        if let Some(parent) = restored_message.as_ref().and_then(|row| row.parent) {
+            sqlx::query("UPDATE messages SET reply_count = reply_count + 1 WHERE id = $1")
+                .bind(parent)
+                .execute(&mut tx)
+                .await
+                .map_err(|e| storage_error("Failed to update thread", e))?;
+        }
+        tx.commit()
+            .await
+            .map_err(|e| storage_error("Failed to restore message", e))?;
+        Ok(restored_message.map(Message::from))
+    }
+
+    async fn purge_messages(&self, deleted_before: Timestamp) -> Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM messages WHERE deleted_at < $1 \
+             AND NOT EXISTS (SELECT 1 FROM messages reply WHERE reply.parent = messages.id)",
+        )
+        .bind(deleted_before)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| storage_error("Failed to purge messages", e))?;
+        Ok(result.rows_affected())
+    }
+
+    async fn create_reaction(
+        &self,
+        message: Id,
+        user: Id,
+        emoji: String,
+        created: Timestamp,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO reactions(message, \"user\", emoji, created) VALUES ($1, $2, $3, $4) \
+             ON CONFLICT DO NOTHING",
+        )
+        .bind(message)
+        .bind(user)
+        .bind(emoji)
+        .bind(created)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| storage_error("Failed to create reaction", e))?;
+        Ok(())
+    }
+
+    async fn delete_reaction(&self, message: Id, user: Id, emoji: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "DELETE FROM reactions WHERE message = $1 AND \"user\" = $2 AND emoji = $3",
+        )
+        .bind(message)
+        .bind(user)
+        .bind(emoji)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| storage_error("Failed to delete reaction", e))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn read_reactions(&self, messages: &[Id]) -> Result<Vec<ReactionCount>> {
+        sqlx::query_as(
+            "SELECT message, emoji, COUNT(*) AS count FROM reactions WHERE message = ANY($1) \
+             GROUP BY message, emoji ORDER BY MIN(created) ASC, emoji COLLATE \"C\" ASC",
+        )
+        .bind(messages)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| storage_error("Failed to read reactions", e))
+    }
+
+    async fn create_event(&self, room: Id, payload: String, created: Timestamp) -> Result<Seq> {
+        let row = sqlx::query(
+            "INSERT INTO events(room, created, payload) VALUES ($1, $2, $3) RETURNING seq",
+        )
+        .bind(room)
+        .bind(created)
+        .bind(payload)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| storage_error("Failed to log event", e))?;
+        Ok(row.get("seq"))
+    }
+
+    async fn read_events(&self, since: Seq, rooms: &[Id], limit: i64) -> Result<Vec<LoggedEvent>> {
+        sqlx::query_as(
+            "SELECT * FROM events WHERE seq > $1 \
+             AND (cardinality($2::INT4[]) = 0 OR room = ANY($2)) \
+             ORDER BY seq ASC LIMIT $3",
+        )
+        .bind(since)
+        .bind(rooms)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| storage_error("Failed to read events", e))
+    }
+
+    async fn read_oldest_event(&self) -> Result<Option<Seq>> {
+        let row = sqlx::query("SELECT MIN(seq) AS seq FROM events")
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read events", e))?;
+        Ok(row.get("seq"))
+    }
+
+    async fn read_last_seq(&self) -> Result<Seq> {
+        sqlx::query_scalar(
+            "SELECT CASE WHEN is_called THEN last_value ELSE 0 END FROM events_seq_seq",
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| storage_error("Failed to read events", e))
+    }
+
+    async fn purge_events(&self, created_before: Timestamp) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM events WHERE created < $1")
+            .bind(created_before)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to purge events", e))?;
+        Ok(result.rows_affected())
+    }
+}
+
+/// This function wraps a storage error when inserting or updating a document with
+/// a unique name, which fails with a validation error if the name is already taken.
+fn unique_error(taken: &str, context: &str, error: sqlx::Error) -> DatabaseError {
+    match error.as_database_error().and_then(|e| e.code()) {
+        Some(code) if code == UNIQUE_VIOLATION => DatabaseError::Validation(taken.to_string()),
+        _ => storage_error(context, error),
+    }
+}
+
+/// This function wraps a storage error with some context about the failed operation.
+fn storage_error(context: &str, error: sqlx::Error) -> DatabaseError {
+    DatabaseError::Storage(format!("{}: {}", context, error))
+}
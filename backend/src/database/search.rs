@@ -0,0 +1,108 @@
+//! This module implements full-text search in Rust, for the stores that don't have
+//! a full-text search engine like the one of postgres.
+//!
+//! It mimics postgres closely enough: a message matches if each search term is the
+//! prefix of one of its words, regardless of case, and snippets are the escaped
+//! text of the message with the matched words wrapped in `<mark>` tags.
+
+use super::{Message, SearchResult};
+use std::cmp::Ordering;
+
+/// This struct holds the terms of a parsed search query.
+pub struct SearchTerms {
+    terms: Vec<String>,
+}
+
+impl SearchTerms {
+    /// This constructor parses the terms of a search query, ignoring punctuation.
+    pub fn new(query: &str) -> Self {
+        let terms = words(query).map(str::to_lowercase).collect();
+        Self { terms }
+    }
+
+    /// This method returns the terms, e.g. to prefilter messages in a store.
+    pub fn terms(&self) -> &[String] {
+        &self.terms
+    }
+
+    /// This method returns the rank of the text if it matches all terms, i.e. the
+    /// proportion of its words matched by any term.
+    pub fn rank(&self, text: &str) -> Option<f32> {
+        if self.terms.is_empty() {
+            return None;
+        }
+        let words: Vec<String> = words(text).map(str::to_lowercase).collect();
+        let is_present = |term: &String| words.iter().any(|word| word.starts_with(term.as_str()));
+        if !self.terms.iter().all(is_present) {
+            return None;
+        }
+        let hits = words.iter().filter(|word| self.is_match(word)).count();
+        Some(hits as f32 / words.len() as f32)
+    }
+
+    /// This method returns the text escaped for HTML, with the matched words
+    /// wrapped in `<mark>` tags.
+    pub fn snippet(&self, text: &str) -> String {
+        let mut snippet = String::with_capacity(text.len());
+        let mut rest = text;
+        for word in words(text) {
+            // Words are subslices of the text, in order, so the offset of the next
+            // word in the rest of the text is the difference of their pointers.
+            let start = word.as_ptr() as usize - rest.as_ptr() as usize;
+            snippet.push_str(&escape(&rest[..start]));
+            if self.is_match(&word.to_lowercase()) {
+                snippet.push_str("<mark>");
+                snippet.push_str(&escape(word));
+                snippet.push_str("</mark>");
+            } else {
+                snippet.push_str(&escape(word));
+            }
+            rest = &rest[start + word.len()..];
+        }
+        snippet.push_str(&escape(rest));
+        snippet
+    }
+
+    /// This method returns the best `limit` results among the input messages,
+    /// sorted by decreasing rank and then anti-chronologically.
+    pub fn search(&self, messages: impl Iterator<Item = Message>, limit: i64) -> Vec<SearchResult> {
+        let mut results: Vec<SearchResult> = messages
+            .filter_map(|message| {
+                let rank = self.rank(&message.text)?;
+                let snippet = self.snippet(&message.text);
+                Some(SearchResult {
+                    message,
+                    rank,
+                    snippet,
+                })
+            })
+            .collect();
+        results.sort_by(|a, b| {
+            let rank = b.rank.partial_cmp(&a.rank).unwrap_or(Ordering::Equal);
+            let a = (a.message.created, a.message.id);
+            rank.then_with(|| (b.message.created, b.message.id).cmp(&a))
+        });
+        results.truncate(limit as usize);
+        results
+    }
+
+    /// This private method returns whether the lowercase word is matched by any term.
+    fn is_match(&self, word: &str) -> bool {
+        self.terms
+            .iter()
+            .any(|term| word.starts_with(term.as_str()))
+    }
+}
+
+/// This function splits the text into words, i.e. runs of alphanumeric characters.
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+}
+
+/// This function escapes the HTML special characters of the text.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,539 @@
+//! This module implements the [`ChatStore`] on top of a local SQLite file.
+
+use super::moderation::Moderated;
+use super::search::SearchTerms;
+use super::{ChatStore, DatabaseError, Id, Message, NewProfile, PageQuery, Profile, Result};
+use super::{LoggedEvent, Seq, Timestamp};
+use super::{MessageRow, NewMessage, ReactionCount, Revision, Room, SearchQuery, SearchResult};
+use crate::config::Config;
+use async_trait::async_trait;
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::SqlitePool;
+use std::str::FromStr;
+
+/// This constant is the SQLite extended error code of a unique constraint violation.
+const UNIQUE_VIOLATION: &str = "2067";
+
+/// This static holds the SQLite schema migrations embedded in the binary.
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// This struct stores all documents in SQLite, using the schema in `migrations/sqlite`.
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    /// This constructor opens the SQLite database, creating the file if it doesn't
+    /// exist yet, and applies any pending migration.
+    pub async fn new(config: &Config) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(&config.database_url)
+            .map_err(|e| storage_error("Invalid database URL", e))?
+            .create_if_missing(true);
+        // In-memory databases share their cache across connections, which fails
+        // with "database table is locked" under concurrent access, so they only
+        // get a single connection. It's never closed, since the database would be
+        // lost with it.
+        let url = &config.database_url;
+        let in_memory = url.contains(":memory:") || url.contains("mode=memory");
+        let pool_options = if in_memory {
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            SqlitePoolOptions::new().max_connections(config.pool_size)
+        };
+        let pool = pool_options
+            .connect_with(options)
+            .await
+            .map_err(|e| storage_error("Failed to open database", e))?;
+        MIGRATOR
+            .run(&pool)
+            .await
+            .map_err(|e| DatabaseError::Storage(format!("Failed to migrate database: {}", e)))?;
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl ChatStore for SqliteStore {
+    async fn create_user(&self, password: String, profile: NewProfile) -> Result<Id> {
+        sqlx::query_scalar(
+            "INSERT INTO users (password, name, color, bio) VALUES (?1, ?2, ?3, ?4) RETURNING id",
+        )
+        .bind(password)
+        .bind(profile.name)
+        .bind(profile.color)
+        .bind(profile.bio)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| unique_error("Name is already taken", "Failed to create user", e))
+    }
+
+    async fn read_password(&self, id: Id) -> Result<Option<String>> {
+        sqlx::query_scalar("SELECT password FROM users WHERE id = ?1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read user", e))
+    }
+
+    async fn update_password(&self, id: Id, password: String) -> Result<()> {
+        sqlx::query("UPDATE users SET password = ?1 WHERE id = ?2")
+            .bind(password)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to update password", e))?;
+        Ok(())
+    }
+
+    async fn read_user(&self, id: Id) -> Result<Option<Profile>> {
+        sqlx::query_as("SELECT id, name, color, bio FROM users WHERE id = ?1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read user", e))
+    }
+
+    async fn read_users(&self, ids: &[Id]) -> Result<Vec<Profile>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        // SQLite has no arrays, so bind each ID to its own placeholder.
+        let placeholders = vec!["?"; ids.len()].join(", ");
+        let sql = format!(
+            "SELECT id, name, color, bio FROM users WHERE id IN ({})",
+            placeholders
+        );
+        let mut query = sqlx::query_as(&sql);
+        for id in ids {
+            query = query.bind(id);
+        }
+        query
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read users", e))
+    }
+
+    async fn update_user(&self, profile: Profile) -> Result<Profile> {
+        sqlx::query_as(
+            "UPDATE users SET name = ?1, color = ?2, bio = ?3 WHERE id = ?4 \
+             RETURNING id, name, color, bio",
+        )
+        .bind(profile.name)
+        .bind(profile.color)
+        .bind(profile.bio)
+        .bind(profile.id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| unique_error("Name is already taken", "Failed to update user", e))
+    }
+
+    async fn create_session(
+        &self,
+        token: String,
+        user: Id,
+        created: Timestamp,
+        expires: Timestamp,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO sessions (token, owner, created, expires) VALUES (?1, ?2, ?3, ?4)",
+        )
+        .bind(token)
+        .bind(user)
+        .bind(created)
+        .bind(expires)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| storage_error("Failed to create session", e))?;
+        Ok(())
+    }
+
+    async fn read_session(&self, token: &str, now: Timestamp) -> Result<Option<Id>> {
+        sqlx::query_scalar("SELECT owner FROM sessions WHERE token = ?1 AND expires > ?2")
+            .bind(token)
+            .bind(now)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read session", e))
+    }
+
+    async fn delete_session(&self, token: &str) -> Result<()> {
+        sqlx::query("DELETE FROM sessions WHERE token = ?1")
+            .bind(token)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to delete session", e))?;
+        Ok(())
+    }
+
+    async fn read_rooms(&self) -> Result<Vec<Room>> {
+        sqlx::query_as("SELECT * FROM rooms ORDER BY name ASC")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read rooms", e))
+    }
+
+    async fn read_room(&self, id: Id) -> Result<Option<Room>> {
+        sqlx::query_as("SELECT * FROM rooms WHERE id = ?1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read room", e))
+    }
+
+    async fn create_room(&self, name: String, created: Timestamp) -> Result<Room> {
+        sqlx::query_as("INSERT INTO rooms (name, created) VALUES (?1, ?2) RETURNING *")
+            .bind(name)
+            .bind(created)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| unique_error("Room already exists", "Failed to create room", e))
+    }
+
+    async fn read_messages(&self, query: PageQuery) -> Result<Vec<Message>> {
+        let sql = if query.forward {
+            "SELECT * FROM messages \
+             WHERE parent IS NULL AND (?4 IS NULL OR room = ?4) \
+             AND (created, id) > (?1, ?2) \
+             ORDER BY created ASC, id ASC LIMIT ?3"
+        } else {
+            "SELECT * FROM messages \
+             WHERE parent IS NULL AND (?4 IS NULL OR room = ?4) \
+             AND (?1 IS NULL OR (created, id) < (?1, ?2)) \
+             ORDER BY created DESC, id DESC LIMIT ?3"
+        };
+        let rows: Vec<MessageRow> = sqlx::query_as(sql)
+            .bind(query.cursor.map(|cursor| cursor.created))
+            .bind(query.cursor.map(|cursor| cursor.id))
+            .bind(query.limit)
+            .bind(query.room)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read messages", e))?;
+        Ok(rows.into_iter().map(Message::from).collect())
+    }
+
+    async fn read_message(&self, id: Id) -> Result<Option<Message>> {
+        let row: Option<MessageRow> = sqlx::query_as("SELECT * FROM messages WHERE id = ?1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read message", e))?;
+        Ok(row.map(Message::from))
+    }
+
+    async fn read_revisions(&self, id: Id) -> Result<Vec<Revision>> {
+        sqlx::query_as("SELECT * FROM message_revisions WHERE message = ?1 ORDER BY id ASC")
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read revisions", e))
+    }
+
+    async fn search_messages(&self, query: SearchQuery) -> Result<Vec<SearchResult>> {
+        let terms = SearchTerms::new(&query.text);
+        if terms.terms().is_empty() {
+            return Ok(Vec::new());
+        }
+        // SQLite has no full-text search without an extension, so candidates are
+        // prefiltered with LIKE and ranked in Rust. LIKE is only case-insensitive for
+        // ASCII, so other terms are left to the ranking.
+        let ascii_terms: Vec<&String> = terms.terms().iter().filter(|t| t.is_ascii()).collect();
+        let mut sql = "SELECT * FROM messages \
+                       WHERE deleted_at IS NULL \
+                       AND (?1 IS NULL OR author = ?1) AND (?2 IS NULL OR room = ?2) \
+                       AND (?3 IS NULL OR created >= ?3) AND (?4 IS NULL OR created < ?4)"
+            .to_string();
+        for i in 0..ascii_terms.len() {
+            sql.push_str(&format!(" AND text LIKE ?{}", i + 5));
+        }
+        let mut candidates = sqlx::query_as(&sql)
+            .bind(query.author)
+            .bind(query.room)
+            .bind(query.since)
+            .bind(query.until);
+        for term in ascii_terms {
+            candidates = candidates.bind(format!("%{}%", term));
+        }
+        let rows: Vec<MessageRow> = candidates
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to search messages", e))?;
+        let messages = rows.into_iter().map(Message::from);
+        Ok(terms.search(messages, query.limit))
+    }
+
+    async fn read_replies(&self, id: Id) -> Result<Vec<Message>> {
+        let rows: Vec<MessageRow> =
+            sqlx::query_as("SELECT * FROM messages WHERE parent = ?1 ORDER BY created ASC, id ASC")
+                .bind(id)
+                .fetch_all(&self.pool)
+                .await
+                .map_err(|e| storage_error("Failed to read replies", e))?;
+        Ok(rows.into_iter().map(Message::from).collect())
+    }
+
+    async fn create_message(&self, message: NewMessage) -> Result<Message> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| storage_error("Failed to create message", e))?;
+        let created_message: MessageRow = sqlx::query_as(
+            "INSERT INTO messages (author, room, parent, text, created, flagged) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6) RETURNING *",
+        )
+        .bind(message.author)
+        .bind(message.room)
+        .bind(message.parent)
+        .bind(message.text)
+        .bind(message.created)
+        .bind(message.flagged)
+        .fetch_one(&mut tx)
+        .await
+        .map_err(|e| storage_error("Failed to create message", e))?;
+        if let Some(parent) = message.parent {
+            sqlx::query("UPDATE messages SET reply_count = reply_count + 1 WHERE id = ?1")
+                .bind(parent)
+                .execute(&mut tx)
+                .await
+                .map_err(|e| storage_error("Failed to update thread", e))?;
+        }
+        tx.commit()
+            .await
+            .map_err(|e| storage_error("Failed to create message", e))?;
+        Ok(created_message.into())
+    }
+
+    async fn update_message(
+        &self,
+        id: Id,
+        text: Moderated,
+        modified: Timestamp,
+    ) -> Result<Message> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| storage_error("Failed to update message", e))?;
+        sqlx::query(
+            "INSERT INTO message_revisions (message, text, created, replaced) \
+             SELECT id, text, COALESCE(modified, created), ?2 FROM messages WHERE id = ?1",
+        )
+        .bind(id)
+        .bind(modified)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| storage_error("Failed to save revision", e))?;
+        let updated_message: MessageRow = sqlx::query_as(
+            "UPDATE messages SET text = ?1, modified = ?2, edit_count = edit_count + 1, \
+             flagged = flagged OR ?4 WHERE id = ?3 RETURNING *",
+        )
+        .bind(text.text)
+        .bind(modified)
+        .bind(id)
+        .bind(text.flagged)
+        .fetch_one(&mut tx)
+        .await
+        .map_err(|e| storage_error("Failed to update message", e))?;
+        tx.commit()
+            .await
+            .map_err(|e| storage_error("Failed to update message", e))?;
+        Ok(updated_message.into())
+    }
+
+    async fn delete_message(&self, id: Id, deleted: Timestamp) -> Result<Message> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| storage_error("Failed to delete message", e))?;
+        let deleted_message: MessageRow = sqlx::query_as(
+            "UPDATE messages SET deleted_at = ?2 \
+             WHERE id = ?1 AND deleted_at IS NULL RETURNING *",
+        )
+        .bind(id)
+        .bind(deleted)
+        .fetch_one(&mut tx)
+        .await
+        .map_err(|e| storage_error("Failed to delete message", e))?;
+        if let Some(parent) = deleted_message.parent {
+            sqlx::query("UPDATE messages SET reply_count = reply_count - 1 WHERE id = ?1")
+                .bind(parent)
+                .execute(&mut tx)
+                .await
+                .map_err(|e| storage_error("Failed to update thread", e))?;
+        }
+        tx.commit()
+            .await
+            .map_err(|e| storage_error("Failed to delete message", e))?;
+        Ok(deleted_message.into())
+    }
+
+    async fn restore_message(&self, id: Id, deleted_since: Timestamp) -> Result<Option<Message>> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| storage_error("Failed to restore message", e))?;
+        let restored_message: Option<MessageRow> = sqlx::query_as(
+            "UPDATE messages SET deleted_at = NULL \
+             WHERE id = ?1 AND deleted_at >= ?2 RETURNING *",
+        )
+        .bind(id)
+        .bind(deleted_since)
+        .fetch_optional(&mut tx)
+        .await
+        .map_err(|e| storage_error("Failed to restore message", e))?;
+        if let Some(parent) = restored_message.as_ref().and_then(|row| row.parent) {
+            sqlx::query("UPDATE messages SET reply_count = reply_count + 1 WHERE id = ?1")
+                .bind(parent)
+                .execute(&mut tx)
+                .await
+                .map_err(|e| storage_error("Failed to update thread", e))?;
+        }
+        tx.commit()
+            .await
+            .map_err(|e| storage_error("Failed to restore message", e))?;
+        Ok(restored_message.map(Message::from))
+    }
+
+    async fn purge_messages(&self, deleted_before: Timestamp) -> Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM messages WHERE deleted_at < ?1 \
+             AND NOT EXISTS (SELECT 1 FROM messages reply WHERE reply.parent = messages.id)",
+        )
+        .bind(deleted_before)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| storage_error("Failed to purge messages", e))?;
+        Ok(result.rows_affected())
+    }
+
+    async fn create_reaction(
+        &self,
+        message: Id,
+        user: Id,
+        emoji: String,
+        created: Timestamp,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO reactions (message, \"user\", emoji, created) VALUES (?1, ?2, ?3, ?4) \
+             ON CONFLICT DO NOTHING",
+        )
+        .bind(message)
+        .bind(user)
+        .bind(emoji)
+        .bind(created)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| storage_error("Failed to create reaction", e))?;
+        Ok(())
+    }
+
+    async fn delete_reaction(&self, message: Id, user: Id, emoji: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "DELETE FROM reactions WHERE message = ?1 AND \"user\" = ?2 AND emoji = ?3",
+        )
+        .bind(message)
+        .bind(user)
+        .bind(emoji)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| storage_error("Failed to delete reaction", e))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn read_reactions(&self, messages: &[Id]) -> Result<Vec<ReactionCount>> {
+        if messages.is_empty() {
+            return Ok(Vec::new());
+        }
+        let placeholders = vec!["?"; messages.len()].join(", ");
+        let sql = format!(
+            "SELECT message, emoji, COUNT(*) AS count FROM reactions WHERE message IN ({}) \
+             GROUP BY message, emoji ORDER BY MIN(created) ASC, emoji ASC",
+            placeholders
+        );
+        let mut query = sqlx::query_as(&sql);
+        for id in messages {
+            query = query.bind(id);
+        }
+        query
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read reactions", e))
+    }
+
+    async fn create_event(&self, room: Id, payload: String, created: Timestamp) -> Result<Seq> {
+        let result = sqlx::query("INSERT INTO events (room, created, payload) VALUES (?1, ?2, ?3)")
+            .bind(room)
+            .bind(created)
+            .bind(payload)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to log event", e))?;
+        Ok(result.last_insert_rowid())
+    }
+
+    async fn read_events(&self, since: Seq, rooms: &[Id], limit: i64) -> Result<Vec<LoggedEvent>> {
+        let mut sql = "SELECT * FROM events WHERE seq > ?".to_string();
+        if !rooms.is_empty() {
+            let placeholders = vec!["?"; rooms.len()].join(", ");
+            sql.push_str(&format!(" AND room IN ({})", placeholders));
+        }
+        sql.push_str(" ORDER BY seq ASC LIMIT ?");
+        let mut query = sqlx::query_as(&sql).bind(since);
+        for room in rooms {
+            query = query.bind(room);
+        }
+        query
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read events", e))
+    }
+
+    async fn read_oldest_event(&self) -> Result<Option<Seq>> {
+        sqlx::query_scalar("SELECT MIN(seq) FROM events")
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to read events", e))
+    }
+
+    async fn read_last_seq(&self) -> Result<Seq> {
+        // AUTOINCREMENT keeps the last sequence number even if the events are deleted.
+        sqlx::query_scalar(
+            "SELECT COALESCE((SELECT seq FROM sqlite_sequence WHERE name = 'events'), 0)",
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| storage_error("Failed to read events", e))
+    }
+
+    async fn purge_events(&self, created_before: Timestamp) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM events WHERE created < ?1")
+            .bind(created_before)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| storage_error("Failed to purge events", e))?;
+        Ok(result.rows_affected())
+    }
+}
+
+/// This function wraps a storage error when inserting or updating a document with
+/// a unique name, which fails with a validation error if the name is already taken.
+fn unique_error(taken: &str, context: &str, error: sqlx::Error) -> DatabaseError {
+    match error.as_database_error().and_then(|e| e.code()) {
+        Some(code) if code == UNIQUE_VIOLATION => DatabaseError::Validation(taken.to_string()),
+        _ => storage_error(context, error),
+    }
+}
+
+/// This function wraps a storage error with some context about the failed operation.
+fn storage_error(context: &str, error: sqlx::Error) -> DatabaseError {
+    DatabaseError::Storage(format!("{}: {}", context, error))
+}
@@ -0,0 +1,160 @@
+//! This module is responsible for the `/events` endpoint.
+//!
+//! It streams the same events as the `/websocket` endpoint as server-sent events,
+//! for clients that can't open websockets, e.g. behind proxies that strip upgrades.
+//! Each event has the sequence number of the event log as its `id`, so that the
+//! browser resumes from the last event it received when it reconnects.
+
+use crate::database::Seq;
+use crate::websocket::{parse_rooms, subscribe, SequencedEvent, Update};
+use crate::{wrap_error, Result, StateExt};
+use axum::extract::Query;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::routing::get;
+use axum::Router;
+use futures::{Stream, StreamExt};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::time::Duration;
+
+/// This constant is the name of the header in which browsers send the ID of the
+/// last event they received when they reconnect.
+const LAST_EVENT_ID: &str = "last-event-id";
+
+/// This struct contains the query parameters of the `/events` endpoint.
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// A comma-separated list of room IDs, e.g. `1,2,3`. If `None`, the client
+    /// receives events from all rooms.
+    room: Option<String>,
+    /// The sequence number of the last event received by the client, to replay the
+    /// events logged since then. The `Last-Event-ID` header takes precedence.
+    since: Option<Seq>,
+}
+
+/// This function builds and returns the router for the `/events` endpoint.
+pub fn make_router() -> Router {
+    Router::new().route("/", get(events_handler))
+}
+
+/// This function handles the `GET /events` requests.
+///
+/// It streams the events of the requested rooms as they're broadcast, after the
+/// events logged since the `Last-Event-ID` header or the `since` parameter, if any.
+/// Keep-alive comments are sent while no event is, so that proxies don't close the
+/// connection.
+async fn events_handler(
+    params: Query<EventsQuery>,
+    state: StateExt,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = std::result::Result<Event, Infallible>>>> {
+    let rooms = parse_rooms(params.0.room.as_deref())?;
+    for &room in &rooms {
+        state.db.read_room(room).await.map_err(wrap_error)?;
+    }
+    let since = match headers.get(LAST_EVENT_ID) {
+        Some(value) => {
+            let value = value.to_str().ok().and_then(|value| value.parse().ok());
+            let error = "Invalid Last-Event-ID header".to_string();
+            Some(value.ok_or((StatusCode::BAD_REQUEST, error))?)
+        }
+        None => params.0.since,
+    };
+    tracing::info!("event stream client connected to rooms {:?}", rooms);
+    let events = subscribe(&rooms, since, &state).map(|update| Ok(make_event(&update)));
+    let interval = Duration::from_secs(state.config.keep_alive_interval);
+    Ok(Sse::new(events).keep_alive(KeepAlive::new().interval(interval)))
+}
+
+/// This function returns the server-sent event of an update, with the sequence
+/// number of the event as its ID, if any.
+fn make_event(update: &Update) -> Event {
+    // Safe unwrap: Update -> JSON serialization cannot fail.
+    let event = Event::default().json_data(update).unwrap();
+    match update {
+        Update::Event(SequencedEvent { seq: Some(seq), .. }) => event.id(seq.to_string()),
+        _ => event,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::CreateMessage;
+    use crate::messages::tests::{send, Method};
+    use crate::websocket::{ChatEvent, SequencedEvent};
+    use reqwest::header::CONTENT_TYPE;
+    use reqwest::{Response, StatusCode};
+    use std::time::Duration;
+
+    /// Read the stream until it contains `count` events, and return them with
+    /// their IDs, skipping keep-alive comments.
+    async fn next_events(response: &mut Response, count: usize) -> Vec<(String, SequencedEvent)> {
+        let mut text = String::new();
+        loop {
+            let events: Vec<_> = text
+                .split("\n\n")
+                .filter(|event| event.contains("data:"))
+                .collect();
+            if events.len() >= count && text.ends_with("\n\n") {
+                return events.into_iter().map(parse_event).collect();
+            }
+            let timeout = Duration::from_secs(5);
+            let chunk = tokio::time::timeout(timeout, response.chunk()).await;
+            let chunk = chunk.expect("timed out waiting for an event");
+            text.push_str(std::str::from_utf8(&chunk.unwrap().unwrap()).unwrap());
+        }
+    }
+
+    fn parse_event(event: &str) -> (String, SequencedEvent) {
+        let field = |name: &str| {
+            event
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        };
+        let data = serde_json::from_str(&field("data:")).unwrap();
+        (field("id:"), data)
+    }
+
+    #[tokio::test]
+    async fn it_streams_and_resumes_events() {
+        let args = ["--keep-alive-interval", "1"];
+        let (client, addr) = crate::tests::start_client_and_server_with(&args).await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let url = format!("http://{}/events", addr);
+        let mut response = client.get(&url).send().await.unwrap();
+        assert_eq!("text/event-stream", response.headers()[CONTENT_TYPE]);
+
+        let create = |text: &str| {
+            Method::Post(CreateMessage {
+                room: None,
+                reply_to: None,
+                text: text.to_string(),
+            })
+        };
+        let message1 = send(&client, addr, &token1, &create("1")).await.unwrap();
+        let (id1, event1) = next_events(&mut response, 1).await.remove(0);
+        assert_eq!(ChatEvent::Created(message1), event1.event);
+        assert_eq!(event1.seq.unwrap().to_string(), id1);
+        drop(response);
+
+        // Keep-alive comments are sent while the stream is idle.
+        let mut response = client.get(&url).send().await.unwrap();
+        let chunk = tokio::time::timeout(Duration::from_secs(5), response.chunk()).await;
+        assert_eq!(b":\n\n", &chunk.unwrap().unwrap().unwrap()[..]);
+        drop(response);
+
+        let message2 = send(&client, addr, &token1, &create("2")).await.unwrap();
+        let request = client.get(&url).header("Last-Event-ID", &id1);
+        let mut response = request.send().await.unwrap();
+        let (_, event2) = next_events(&mut response, 1).await.remove(0);
+        assert_eq!(ChatEvent::Created(message2), event2.event);
+
+        let request = client.get(&url).header("Last-Event-ID", "invalid");
+        let response = request.send().await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    }
+}
@@ -1,30 +1,59 @@
 //! ChitChat – A simple web app to engage in trivial matters, i.e. to chitchat.
 
+mod config;
 mod database;
+mod events;
 mod messages;
+mod moderation;
+mod presence;
+mod ratelimit;
+mod rooms;
+mod sessions;
 mod users;
 mod websocket;
 
-use crate::database::{Database, Message};
+use crate::config::{Command, Config};
+use crate::database::{Database, DatabaseError, Id};
+use crate::presence::Presence;
+use crate::ratelimit::{RateLimitLayer, RateLimiter};
+use crate::websocket::{SequencedEvent, TypingThrottle};
 use axum::extract::Extension;
-use axum::http::StatusCode;
+use axum::http::{HeaderValue, StatusCode};
 use axum::routing::get_service;
 use axum::{AddExtensionLayer, Router, Server};
+use clap::Parser;
+use parking_lot::Mutex;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::broadcast::Sender;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::Mutex as AsyncMutex;
 use tower::ServiceBuilder;
-use tower_http::cors::{any, CorsLayer};
+use tower_http::cors::{any, CorsLayer, Origin};
 use tower_http::services::{ServeDir, ServeFile};
 
 /// This struct holds the global state shared across all route handlers
 /// and lives for the entire duration of the application.
 pub struct State {
-    /// Handle to the postgres connection pool.
+    /// Runtime configuration of the application.
+    config: Config,
+    /// Handle to the database.
     db: Database,
-    /// Sending-half of the channel used to broadcast messages to all
+    /// Token buckets of the clients of the rate-limited routes.
+    limiter: RateLimiter,
+    /// Sending-half of the channel used to broadcast events to all
     /// connected websocket clients.
-    tx: Sender<Message>,
+    tx: Sender<SequencedEvent>,
+    /// Sending-halves of the channels used to broadcast events to the websocket
+    /// clients subscribed to a given room, indexed by room ID.
+    rooms: Mutex<HashMap<Id, Sender<SequencedEvent>>>,
+    /// Lock held while an event is logged and broadcast, so that events are
+    /// broadcast in the order of their sequence numbers.
+    event_log: AsyncMutex<()>,
+    /// Authenticated websocket connections of the online users.
+    presence: Presence,
+    /// Last typing indicators of the users, to throttle them.
+    typing: TypingThrottle,
 }
 
 /// This type alias is used by all route handlers that are fallible.
@@ -35,11 +64,31 @@ pub type StateExt = Extension<Arc<State>>;
 
 impl State {
     /// This constructor, called once at startup, initializes the global state.
-    fn new() -> Self {
-        Self {
-            db: Database::new(),
-            tx: tokio::sync::broadcast::channel(1000).0,
-        }
+    async fn new(config: &Config) -> std::result::Result<Self, DatabaseError> {
+        Ok(Self {
+            config: config.clone(),
+            db: Database::new(config).await?,
+            limiter: RateLimiter::new(config.rate_limits.clone()),
+            tx: tokio::sync::broadcast::channel(config.channel_capacity).0,
+            rooms: Mutex::new(HashMap::new()),
+            event_log: AsyncMutex::new(()),
+            presence: Presence::default(),
+            typing: TypingThrottle::default(),
+        })
+    }
+
+    /// This method returns a receiving-half of the channel of the input room,
+    /// creating it if no websocket client has subscribed to this room yet.
+    ///
+    /// The receiver is created while the lock is held, so that the channel can't be
+    /// dropped in between for lack of receivers.
+    fn subscribe_room(&self, room: Id) -> Receiver<SequencedEvent> {
+        let mut rooms = self.rooms.lock();
+        let capacity = self.config.channel_capacity;
+        let tx = rooms
+            .entry(room)
+            .or_insert_with(|| tokio::sync::broadcast::channel(capacity).0);
+        tx.subscribe()
     }
 }
 
@@ -49,38 +98,79 @@ async fn main() {
     // Initialize tracing.
     tracing_subscriber::fmt::init();
 
+    // Load the configuration from the command-line arguments and environment.
+    let config = Config::parse();
+
+    // Migrate the database without starting the server, if requested.
+    if let Some(Command::Migrate) = config.command {
+        if let Err(error) = Database::new(&config).await {
+            tracing::error!("fatal migration error: {}", error);
+            std::process::exit(1);
+        }
+        tracing::info!("database is up to date");
+        return;
+    }
+
     // Initialize the top-level app router.
-    let app = make_app_router();
+    let app = match make_app_router(&config).await {
+        Ok(app) => app,
+        Err(error) => {
+            tracing::error!("fatal startup error: {}", error);
+            std::process::exit(1);
+        }
+    };
 
-    // Start the hyper server on port 3000.
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
+    // Start the hyper server on the configured address.
+    let addr = config.bind_address;
     tracing::info!("server listening on {}", addr);
-    if let Err(error) = Server::bind(&addr).serve(app.into_make_service()).await {
+    let service = app.into_make_service_with_connect_info::<SocketAddr, _>();
+    if let Err(error) = Server::bind(&addr).serve(service).await {
         tracing::error!("fatal server error: {}", error);
     }
 }
 
 /// This function builds and returns the top-level app router.
-fn make_app_router() -> Router {
-    let state = Arc::new(State::new());
-    let index = ServeFile::new("static/index.html");
-    let assets = ServeDir::new("static");
-    Router::new()
+async fn make_app_router(config: &Config) -> std::result::Result<Router, DatabaseError> {
+    let state = Arc::new(State::new(config).await?);
+    tokio::spawn(messages::purge_deleted_messages(state.clone()));
+    tokio::spawn(ratelimit::evict_idle_buckets(state.clone()));
+    tokio::spawn(websocket::evict_idle_typists(state.clone()));
+    let index = ServeFile::new(config.static_dir.join("index.html"));
+    let assets = ServeDir::new(&config.static_dir);
+    let router = Router::new()
         .route("/", get_service(index).handle_error(wrap_500))
         .nest("/static", get_service(assets).handle_error(wrap_500))
+        .nest("/events", events::make_router())
         .nest("/messages", messages::make_router())
+        .nest("/moderation", moderation::make_router())
+        .nest("/presence", presence::make_router())
+        .nest("/rooms", rooms::make_router())
+        .nest("/sessions", sessions::make_router())
         .nest("/users", users::make_router())
         .nest("/websocket", websocket::make_router())
         .layer(
             ServiceBuilder::new()
-                .layer(
-                    CorsLayer::new()
-                        .allow_headers(any())
-                        .allow_methods(any())
-                        .allow_origin(any()),
-                )
-                .layer(AddExtensionLayer::new(state)),
-        )
+                .layer(make_cors_layer(config))
+                .layer(AddExtensionLayer::new(state))
+                .layer(RateLimitLayer),
+        );
+    Ok(router)
+}
+
+/// This function builds the CORS layer allowing the configured origins.
+fn make_cors_layer(config: &Config) -> CorsLayer {
+    let cors = CorsLayer::new().allow_headers(any()).allow_methods(any());
+    if config.cors_origins.iter().any(|origin| origin == "*") {
+        return cors.allow_origin(any());
+    }
+    let origins = config.cors_origins.iter().filter_map(|origin| {
+        let value = HeaderValue::from_str(origin);
+        if value.is_err() {
+            tracing::warn!("ignoring invalid CORS origin: {}", origin);
+        }
+        value.ok()
+    });
+    cors.allow_origin(Origin::list(origins))
 }
 
 /// This function wraps IO errors when serving static file requests
@@ -92,31 +182,100 @@ async fn wrap_500(error: std::io::Error) -> (StatusCode, &'static str) {
     (StatusCode::INTERNAL_SERVER_ERROR, "Something's wrong!")
 }
 
-/// This function wraps errors when serving API requests
-/// in a "400 Bad Request" http response.
-pub fn wrap_400(error: String) -> (StatusCode, String) {
-    tracing::warn!("BAD_REQUEST: {}", error);
-    (StatusCode::BAD_REQUEST, error)
+/// This function wraps errors when serving API requests in the http response
+/// matching the kind of error, e.g. "404 Not Found" for a missing message.
+///
+/// Storage errors are only logged, since they may contain SQL or connection
+/// details, and the client gets a generic message instead.
+pub fn wrap_error(error: DatabaseError) -> (StatusCode, String) {
+    let status = match error {
+        DatabaseError::NotFound(_) => StatusCode::NOT_FOUND,
+        DatabaseError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+        DatabaseError::Forbidden(_) => StatusCode::FORBIDDEN,
+        DatabaseError::Validation(_) => StatusCode::BAD_REQUEST,
+        DatabaseError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    if status.is_server_error() {
+        tracing::error!("{}: {}", status, error);
+        return (status, "Something's wrong!".to_string());
+    }
+    tracing::warn!("{}: {}", status, error);
+    (status, error.to_string())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::database::POSTGRES_URI;
+    use crate::config::Config;
+    use crate::database::{Database, MessagePage, Profile, User, DEFAULT_ROOM};
     use axum::Server;
+    use clap::Parser;
     use reqwest::Client;
     use sqlx::{Connection, PgConnection};
     use std::net::{SocketAddr, TcpListener};
+    use std::sync::atomic::{AtomicU32, Ordering};
     use std::time::Duration;
 
+    /// This constant is the environment variable selecting the database the tests
+    /// run against. It defaults to an in-memory database.
+    const TEST_DATABASE_URL: &str = "CHITCHAT_TEST_DATABASE_URL";
+
     /// Initialize the reqwest client, and the hyper server on a random port.
     ///
     /// Each unit test calls this function, which is why we need to start the
-    /// hyper server on a random port. Also, we need to reset both tables because
-    /// each unit test assumes that there are no messages and no users.
+    /// hyper server on a random port. Also, each unit test assumes that there are
+    /// no messages, no users, and only the default room. In-memory databases (i.e.
+    /// `memory://` and `sqlite::memory:`) are fresh for each server, whereas each
+    /// server gets a fresh schema in postgres, migrated by `Database::new`.
     pub async fn start_client_and_server() -> (Client, SocketAddr) {
+        start_client_and_server_with(&[]).await
+    }
+
+    /// Initialize the reqwest client, and the hyper server on a random port with
+    /// additional command-line arguments, e.g. `["--max-text-length", "10"]`.
+    pub async fn start_client_and_server_with(args: &[&str]) -> (Client, SocketAddr) {
+        let mut database_url = test_database_url();
+        if database_url.starts_with("postgres") {
+            database_url = create_postgres_schema(&database_url).await;
+        }
+        start_client_and_server_on(&database_url, args).await
+    }
+
+    /// Return the URL of the database the tests run against.
+    fn test_database_url() -> String {
+        std::env::var(TEST_DATABASE_URL).unwrap_or_else(|_| "memory://".to_string())
+    }
+
+    /// Initialize the reqwest client, and the hyper server on a random port using the
+    /// input database as-is.
+    async fn start_client_and_server_on(database_url: &str, args: &[&str]) -> (Client, SocketAddr) {
+        let default_args = ["chitchat", "--database-url", database_url];
+        let args = default_args.iter().chain(args).copied();
+        let config = Config::parse_from(args);
+
+        let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let app = crate::make_app_router(&config).await.unwrap();
+        tokio::spawn(async move {
+            Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service_with_connect_info::<SocketAddr, _>())
+                .await
+                .unwrap()
+        });
+
+        (Client::new(), server_addr)
+    }
+
+    /// Create a fresh postgres schema, retrying until postgres is up, and return
+    /// the database URL using it.
+    ///
+    /// The schemas of previous test runs are dropped when the first schema of the
+    /// current test run is created.
+    async fn create_postgres_schema(database_url: &str) -> String {
+        static SCHEMA_SEQ: AtomicU32 = AtomicU32::new(0);
         let mut retries = 0;
         let mut conn = loop {
-            match PgConnection::connect(POSTGRES_URI).await {
+            match PgConnection::connect(database_url).await {
                 Ok(conn) => break conn,
                 Err(_) => {
                     retries += 1;
@@ -127,22 +286,107 @@ mod tests {
                 }
             }
         };
-        sqlx::query("TRUNCATE messages, users CASCADE")
-            .execute(&mut conn)
+        let run = format!("test_{}_", std::process::id());
+        let seqno = SCHEMA_SEQ.fetch_add(1, Ordering::Relaxed);
+        if seqno == 0 {
+            let stale: Vec<String> = sqlx::query_scalar(
+                "SELECT nspname::TEXT FROM pg_namespace \
+                 WHERE nspname LIKE 'test\\_%' AND nspname NOT LIKE $1 || '%'",
+            )
+            .bind(&run)
+            .fetch_all(&mut conn)
             .await
             .unwrap();
+            for schema in stale {
+                let query = format!("DROP SCHEMA IF EXISTS {} CASCADE", schema);
+                sqlx::query(&query).execute(&mut conn).await.unwrap();
+            }
+        }
+        let schema = format!("{}{}", run, seqno);
+        let query = format!("CREATE SCHEMA {}", schema);
+        sqlx::query(&query).execute(&mut conn).await.unwrap();
+        let separator = if database_url.contains('?') { '&' } else { '?' };
+        format!(
+            "{}{}options=-c%20search_path%3D{}",
+            database_url, separator, schema
+        )
+    }
 
-        let listener = TcpListener::bind("0.0.0.0:0").unwrap();
-        let server_addr = listener.local_addr().unwrap();
-        let app = crate::make_app_router();
-        tokio::spawn(async move {
-            Server::from_tcp(listener)
-                .unwrap()
-                .serve(app.into_make_service())
-                .await
-                .unwrap()
-        });
+    #[tokio::test]
+    async fn it_detects_purged_events() {
+        let mut database_url = test_database_url();
+        if database_url.starts_with("postgres") {
+            database_url = create_postgres_schema(&database_url).await;
+        }
+        let args = ["chitchat", "--database-url", &database_url];
+        let args = args.iter().chain(&["--message-retention", "1"]).copied();
+        let db = Database::new(&Config::parse_from(args)).await.unwrap();
+        let seq1 = db
+            .create_event(DEFAULT_ROOM, "{}".to_string())
+            .await
+            .unwrap();
+        let seq2 = db
+            .create_event(DEFAULT_ROOM, "{}".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            1,
+            db.read_events(seq1, &[], 10).await.unwrap().unwrap().len()
+        );
 
-        (Client::new(), server_addr)
+        // Timestamps are in seconds, so wait until the events are older than 1 second.
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert_eq!(2, db.purge_events().await.unwrap());
+        assert!(db.read_events(seq1, &[], 10).await.unwrap().is_none());
+        assert!(db
+            .read_events(seq2, &[], 10)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_migrates_legacy_databases() {
+        let database_url = test_database_url();
+        if !database_url.starts_with("postgres") {
+            return;
+        }
+        // Create and fill a database with the former `postgres/init.sql` schema.
+        let database_url = create_postgres_schema(&database_url).await;
+        let mut conn = PgConnection::connect(&database_url).await.unwrap();
+        let legacy = [
+            "CREATE TABLE users(id SERIAL PRIMARY KEY, password VARCHAR(50) NOT NULL)",
+            "CREATE TABLE messages(id SERIAL PRIMARY KEY, author INT4 REFERENCES users (id), \
+             text VARCHAR(100) NOT NULL, created INT8 NOT NULL, modified INT8)",
+            "INSERT INTO users(password) VALUES ('secret')",
+            "INSERT INTO messages(author, text, created) VALUES (1, 'Hello', 1)",
+        ];
+        for query in legacy {
+            sqlx::query(query).execute(&mut conn).await.unwrap();
+        }
+
+        let (client, addr) = start_client_and_server_on(&database_url, &[]).await;
+        let user = User {
+            id: 1,
+            password: "secret".to_string(),
+        };
+        let url = format!("http://{}/users/1", addr);
+        let profile: Profile = client.get(&url).send().await.unwrap().json().await.unwrap();
+        assert_eq!("user1", profile.name);
+        let url = format!("http://{}/messages", addr);
+        let page: MessagePage = client.get(&url).send().await.unwrap().json().await.unwrap();
+        assert_eq!(DEFAULT_ROOM, page.messages[0].room);
+        assert_eq!("Hello", page.messages[0].text);
+
+        // The plaintext password is hashed on the first login, and still works after.
+        crate::sessions::tests::create_session(&client, addr, &user).await;
+        let query = "SELECT password FROM users WHERE id = 1";
+        let hash: String = sqlx::query_scalar(query)
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        assert!(hash.starts_with("$argon2"));
+        crate::sessions::tests::create_session(&client, addr, &user).await;
     }
 }
@@ -1,98 +1,383 @@
 //! This module is responsible for the `/messages` endpoint.
 
-use crate::database::{CreateMessage, DeleteMessage, Message, UpdateMessage};
-use crate::websocket::broadcast_message;
-use crate::{wrap_400, Result, StateExt};
-use axum::routing::get;
+use crate::database::{
+    CreateMessage, CreateReaction, DeleteMessage, Id, Message, MessagePage, ReadMessages, Revision,
+    SearchMessages, SearchResults, Thread, UpdateMessage,
+};
+use crate::sessions::AuthUser;
+use crate::websocket::{broadcast_message, ChatEvent};
+use crate::{wrap_error, Result, State, StateExt};
+use axum::extract::{Path, Query};
+use axum::routing::{delete, get, post};
 use axum::{Json, Router};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// This constant is the period between two purges of the deleted messages.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 /// This function builds and returns the router for the `/messages` endpoint.
 pub fn make_router() -> Router {
-    Router::new().route(
-        "/",
-        get(read_messages)
-            .post(create_message)
-            .put(update_message)
-            .delete(delete_message),
-    )
+    Router::new()
+        .route(
+            "/",
+            get(read_messages)
+                .post(create_message)
+                .put(update_message)
+                .delete(delete_message),
+        )
+        .route("/search", get(search_messages))
+        .route("/:id/thread", get(read_thread))
+        .route("/:id/revisions", get(read_revisions))
+        .route("/:id/restore", post(restore_message))
+        .route("/:id/reactions", post(create_reaction))
+        .route("/:id/reactions/:emoji", delete(delete_reaction))
 }
 
 /// This function handles the `GET /messages` requests.
 ///
-/// It retrieves and returns all messages in chronological order.
-async fn read_messages(state: StateExt) -> Result<Json<Vec<Message>>> {
-    let all_messages = state.db.read_messages().await.map_err(wrap_400)?;
-    Ok(Json(all_messages))
+/// It retrieves and returns a page of messages in chronological order, along with
+/// the cursor to read the next page, if any.
+async fn read_messages(params: Query<ReadMessages>, state: StateExt) -> Result<Json<MessagePage>> {
+    let page = state.db.read_messages(params.0).await.map_err(wrap_error)?;
+    Ok(Json(page))
+}
+
+/// This function handles the `GET /messages/search` requests.
+///
+/// It retrieves and returns the messages matching the full-text search, optionally
+/// filtered by author, room and date range, with the best matches first and their
+/// matched words highlighted.
+async fn search_messages(
+    params: Query<SearchMessages>,
+    state: StateExt,
+) -> Result<Json<SearchResults>> {
+    let results = state
+        .db
+        .search_messages(params.0)
+        .await
+        .map_err(wrap_error)?;
+    Ok(Json(results))
+}
+
+/// This function handles the `GET /messages/:id/thread` requests.
+///
+/// It retrieves and returns the thread of the message, i.e. the top-level message
+/// and all its replies in chronological order.
+async fn read_thread(Path(id): Path<Id>, state: StateExt) -> Result<Json<Thread>> {
+    let thread = state.db.read_thread(id).await.map_err(wrap_error)?;
+    Ok(Json(thread))
+}
+
+/// This function handles the `GET /messages/:id/revisions` requests.
+///
+/// It retrieves and returns the former texts of the message in chronological order,
+/// i.e. what the message said before each of its edits.
+async fn read_revisions(Path(id): Path<Id>, state: StateExt) -> Result<Json<Vec<Revision>>> {
+    let revisions = state.db.read_revisions(id).await.map_err(wrap_error)?;
+    Ok(Json(revisions))
 }
 
 /// This function handles the `POST /messages` requests.
 ///
-/// It attempts to create a new message. If successful, it broadcasts the created
-/// message to all connected clients and returns it. Otherwise, it returns a 400.
-async fn create_message(params: Json<CreateMessage>, state: StateExt) -> Result<Json<Message>> {
-    let created_message = state.db.create_message(params.0).await.map_err(wrap_400)?;
-    broadcast_message(created_message.clone(), &state);
+/// It attempts to create a new message, or a reply if `reply_to` is set, and
+/// returns it. Otherwise, it returns an error.
+async fn create_message(
+    user: AuthUser,
+    params: Json<CreateMessage>,
+    state: StateExt,
+) -> Result<Json<Message>> {
+    let created_message = create_and_broadcast(user.id, params.0, &state).await?;
     Ok(Json(created_message))
 }
 
 /// This function handles the `PUT /messages` requests.
 ///
-/// It attempts to update an existing message. If successful, it broadcasts the updated
-/// message to all connected clients and returns it. Otherwise, it returns a 400.
-async fn update_message(params: Json<UpdateMessage>, state: StateExt) -> Result<Json<Message>> {
-    let updated_message = state.db.update_message(params.0).await.map_err(wrap_400)?;
-    broadcast_message(updated_message.clone(), &state);
+/// It attempts to update an existing message and returns it. Otherwise, it returns
+/// an error.
+async fn update_message(
+    user: AuthUser,
+    params: Json<UpdateMessage>,
+    state: StateExt,
+) -> Result<Json<Message>> {
+    let updated_message = update_and_broadcast(user.id, params.0, &state).await?;
     Ok(Json(updated_message))
 }
 
 /// This function handles the `DELETE /messages` requests.
 ///
-/// It attempts to delete an existing message. If successful, it returns the deleted
-/// message. Otherwise, it returns a 400.
-async fn delete_message(params: Json<DeleteMessage>, state: StateExt) -> Result<Json<Message>> {
-    let deleted_message = state.db.delete_message(params.0).await.map_err(wrap_400)?;
+/// It attempts to delete an existing message, which is kept as a tombstone so that
+/// its replies stay in place, and returns the tombstone. Otherwise, it returns an
+/// error.
+async fn delete_message(
+    user: AuthUser,
+    params: Json<DeleteMessage>,
+    state: StateExt,
+) -> Result<Json<Message>> {
+    let deleted_message = delete_and_broadcast(user.id, params.0, &state).await?;
     Ok(Json(deleted_message))
 }
 
+/// This function creates a message on behalf of the user, for both the REST and
+/// websocket APIs. If successful, it broadcasts the created message, and the
+/// updated thread for replies, to all connected clients and returns it.
+pub async fn create_and_broadcast(
+    user: Id,
+    params: CreateMessage,
+    state: &StateExt,
+) -> Result<Message> {
+    let created_message = state
+        .db
+        .create_message(user, params)
+        .await
+        .map_err(wrap_error)?;
+    broadcast_message(ChatEvent::Created(created_message.clone()), state).await;
+    broadcast_thread(&created_message, state).await;
+    Ok(created_message)
+}
+
+/// This function updates a message on behalf of its author, for both the REST and
+/// websocket APIs. If successful, it broadcasts the updated message to all connected
+/// clients and returns it.
+pub async fn update_and_broadcast(
+    user: Id,
+    params: UpdateMessage,
+    state: &StateExt,
+) -> Result<Message> {
+    let updated_message = state
+        .db
+        .update_message(user, params)
+        .await
+        .map_err(wrap_error)?;
+    broadcast_message(ChatEvent::Updated(updated_message.clone()), state).await;
+    Ok(updated_message)
+}
+
+/// This function deletes a message on behalf of its author, for both the REST and
+/// websocket APIs. If successful, it broadcasts the ID of the deleted message, and
+/// the updated thread for replies, to all connected clients and returns the tombstone.
+pub async fn delete_and_broadcast(
+    user: Id,
+    params: DeleteMessage,
+    state: &StateExt,
+) -> Result<Message> {
+    let deleted_message = state
+        .db
+        .delete_message(user, params)
+        .await
+        .map_err(wrap_error)?;
+    let id = deleted_message.id;
+    let room = deleted_message.room;
+    broadcast_message(ChatEvent::Deleted { id, room }, state).await;
+    broadcast_thread(&deleted_message, state).await;
+    Ok(deleted_message)
+}
+
+/// This function handles the `POST /messages/:id/restore` requests.
+///
+/// It attempts to restore a message deleted by the authenticated user within the
+/// restore window. If successful, it broadcasts the restored message, and the
+/// updated thread for replies, to all connected clients and returns it. Otherwise,
+/// it returns an error.
+async fn restore_message(
+    user: AuthUser,
+    Path(id): Path<Id>,
+    state: StateExt,
+) -> Result<Json<Message>> {
+    let restored_message = state
+        .db
+        .restore_message(user.id, id)
+        .await
+        .map_err(wrap_error)?;
+    broadcast_message(ChatEvent::Restored(restored_message.clone()), &state).await;
+    broadcast_thread(&restored_message, &state).await;
+    Ok(Json(restored_message))
+}
+
+/// This function handles the `POST /messages/:id/reactions` requests.
+///
+/// It attempts to add the reaction of the authenticated user to the message. If
+/// successful, it broadcasts the new count of reactions with the emoji to all
+/// connected clients and returns the message. Otherwise, it returns an error.
+async fn create_reaction(
+    user: AuthUser,
+    Path(id): Path<Id>,
+    params: Json<CreateReaction>,
+    state: StateExt,
+) -> Result<Json<Message>> {
+    let emoji = params.emoji.clone();
+    let message = state
+        .db
+        .create_reaction(user.id, id, params.0)
+        .await
+        .map_err(wrap_error)?;
+    let event = ChatEvent::ReactionAdded {
+        id,
+        room: message.room,
+        user: user.id,
+        count: reaction_count(&message, &emoji),
+        emoji,
+    };
+    broadcast_message(event, &state).await;
+    Ok(Json(message))
+}
+
+/// This function handles the `DELETE /messages/:id/reactions/:emoji` requests.
+///
+/// It attempts to remove the reaction of the authenticated user to the message. If
+/// successful, it broadcasts the new count of reactions with the emoji to all
+/// connected clients and returns the message. Otherwise, it returns an error.
+async fn delete_reaction(
+    user: AuthUser,
+    Path((id, emoji)): Path<(Id, String)>,
+    state: StateExt,
+) -> Result<Json<Message>> {
+    let message = state
+        .db
+        .delete_reaction(user.id, id, &emoji)
+        .await
+        .map_err(wrap_error)?;
+    let event = ChatEvent::ReactionRemoved {
+        id,
+        room: message.room,
+        user: user.id,
+        count: reaction_count(&message, &emoji),
+        emoji,
+    };
+    broadcast_message(event, &state).await;
+    Ok(Json(message))
+}
+
+/// This function returns the number of reactions with the emoji to the message.
+fn reaction_count(message: &Message, emoji: &str) -> i64 {
+    let reaction = message.reactions.iter().find(|r| r.emoji == emoji);
+    reaction.map_or(0, |reaction| reaction.count)
+}
+
+/// This function broadcasts the reply count of the thread of the input message to
+/// all connected clients, if the message is a reply.
+async fn broadcast_thread(message: &Message, state: &StateExt) {
+    let parent = match message.parent {
+        Some(parent) => parent,
+        None => return,
+    };
+    match state.db.read_message(parent).await {
+        Ok(thread) => {
+            let event = ChatEvent::ThreadUpdated {
+                id: thread.id,
+                room: thread.room,
+                reply_count: thread.reply_count,
+            };
+            broadcast_message(event, state).await;
+        }
+        Err(error) => tracing::warn!("failed to read thread {}: {}", parent, error),
+    }
+}
+
+/// This function runs in the background for the entire duration of the application,
+/// and periodically purges the messages deleted, and the events logged, for longer
+/// than the retention period.
+pub async fn purge_deleted_messages(state: Arc<State>) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match state.db.purge_messages().await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("purged {} deleted messages", count),
+            Err(error) => tracing::warn!("failed to purge deleted messages: {}", error),
+        }
+        match state.db.purge_events().await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("purged {} logged events", count),
+            Err(error) => tracing::warn!("failed to purge logged events: {}", error),
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
-    use crate::database::{CreateMessage, DeleteMessage, Message, UpdateMessage};
+    use crate::database::{
+        CreateMessage, CreateReaction, Cursor, DeleteMessage, Id, Message, MessagePage, Reaction,
+        Revision, SearchResults, Thread, UpdateMessage,
+    };
     use reqwest::{Client, StatusCode};
     use std::net::SocketAddr;
 
     const TEXT: &str = "Hello, World!";
 
-    enum Method {
+    pub enum Method {
         Delete(DeleteMessage),
         Post(CreateMessage),
         Put(UpdateMessage),
     }
 
-    async fn send(client: &Client, addr: SocketAddr, method: &Method) -> Result<Message, String> {
+    pub async fn send(
+        client: &Client,
+        addr: SocketAddr,
+        token: &str,
+        method: &Method,
+    ) -> Result<Message, (StatusCode, String)> {
         let url = format!("http://{}/messages", addr);
         let request = match method {
             Method::Delete(params) => client.delete(&url).json(params),
             Method::Post(params) => client.post(&url).json(params),
             Method::Put(params) => client.put(&url).json(params),
         };
+        let response = request.bearer_auth(token).send().await.unwrap();
+        match response.status() {
+            StatusCode::OK => Ok(response.json().await.unwrap()),
+            status => Err((status, response.text().await.unwrap())),
+        }
+    }
+
+    async fn read(client: &Client, addr: SocketAddr, query: &str) -> MessagePage {
+        let url = format!("http://{}/messages?{}", addr, query);
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        response.json().await.unwrap()
+    }
+
+    fn create(text: &str) -> Method {
+        Method::Post(CreateMessage {
+            room: None,
+            reply_to: None,
+            text: text.to_string(),
+        })
+    }
+
+    fn reply(reply_to: Id, text: &str) -> Method {
+        Method::Post(CreateMessage {
+            room: None,
+            reply_to: Some(reply_to),
+            text: text.to_string(),
+        })
+    }
+
+    pub async fn react(
+        client: &Client,
+        addr: SocketAddr,
+        token: &str,
+        id: Id,
+        emoji: &str,
+    ) -> Result<Message, (StatusCode, String)> {
+        let url = format!("http://{}/messages/{}/reactions", addr, id);
+        let params = CreateReaction {
+            emoji: emoji.to_string(),
+        };
+        let request = client.post(&url).json(&params).bearer_auth(token);
         let response = request.send().await.unwrap();
         match response.status() {
             StatusCode::OK => Ok(response.json().await.unwrap()),
-            StatusCode::BAD_REQUEST => Err(response.text().await.unwrap()),
-            _ => panic!("unexpected status code"),
+            status => Err((status, response.text().await.unwrap())),
         }
     }
 
     #[tokio::test]
     async fn it_creates_message() {
         let (client, addr) = crate::tests::start_client_and_server().await;
-        let user1 = crate::users::tests::create_user(&client, addr).await;
+        let (user1, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
 
-        let method = Method::Post(CreateMessage {
-            user: user1.clone(),
-            text: TEXT.to_string(),
-        });
-        let message1 = send(&client, addr, &method).await.unwrap();
+        let message1 = send(&client, addr, &token1, &create(TEXT)).await.unwrap();
 
         assert_eq!(user1.id, message1.author);
         assert_eq!(TEXT, message1.text);
@@ -102,20 +387,15 @@ pub mod tests {
     #[tokio::test]
     async fn it_updates_message() {
         let (client, addr) = crate::tests::start_client_and_server().await;
-        let user1 = crate::users::tests::create_user(&client, addr).await;
+        let (user1, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
 
-        let method = Method::Post(CreateMessage {
-            user: user1.clone(),
-            text: TEXT.to_string(),
-        });
-        let message1 = send(&client, addr, &method).await.unwrap();
+        let message1 = send(&client, addr, &token1, &create(TEXT)).await.unwrap();
 
         let method = Method::Put(UpdateMessage {
             message: message1.id,
-            user: user1.clone(),
             text: TEXT.to_string(),
         });
-        let updated_message1 = send(&client, addr, &method).await.unwrap();
+        let updated_message1 = send(&client, addr, &token1, &method).await.unwrap();
 
         assert_eq!(user1.id, updated_message1.author);
         assert_eq!(TEXT, updated_message1.text);
@@ -123,77 +403,389 @@ pub mod tests {
         assert!(updated_message1.modified.is_some());
     }
 
+    #[tokio::test]
+    async fn it_keeps_revisions_of_updated_message() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+
+        let message1 = send(&client, addr, &token1, &create("first"))
+            .await
+            .unwrap();
+        assert_eq!(0, message1.edit_count);
+        let mut updated_message1 = message1.clone();
+        for text in ["second", "third"] {
+            let method = Method::Put(UpdateMessage {
+                message: message1.id,
+                text: text.to_string(),
+            });
+            updated_message1 = send(&client, addr, &token1, &method).await.unwrap();
+        }
+        assert_eq!(2, updated_message1.edit_count);
+        assert_eq!("third", updated_message1.text);
+
+        let url = format!("http://{}/messages/{}/revisions", addr, message1.id);
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let revisions: Vec<Revision> = response.json().await.unwrap();
+        let texts: Vec<&str> = revisions.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(vec!["first", "second"], texts);
+        assert_eq!(message1.created, revisions[0].created);
+        assert_eq!(updated_message1.modified, Some(revisions[1].replaced));
+
+        let url = format!("http://{}/messages/42/revisions", addr);
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+
     #[tokio::test]
     async fn it_deletes_message() {
         let (client, addr) = crate::tests::start_client_and_server().await;
-        let user1 = crate::users::tests::create_user(&client, addr).await;
+        let (user1, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
 
-        let method = Method::Post(CreateMessage {
-            user: user1.clone(),
-            text: TEXT.to_string(),
-        });
-        let message1 = send(&client, addr, &method).await.unwrap();
+        let message1 = send(&client, addr, &token1, &create(TEXT)).await.unwrap();
 
         let method = Method::Delete(DeleteMessage {
             message: message1.id,
-            user: user1.clone(),
         });
-        let deleted = send(&client, addr, &method).await.unwrap();
+        let deleted = send(&client, addr, &token1, &method).await.unwrap();
 
         assert_eq!(user1.id, deleted.author);
-        assert_eq!(TEXT, deleted.text);
+        assert_eq!("", deleted.text);
         assert_eq!(message1.created, deleted.created);
-        assert!(message1.modified.is_none());
+        assert!(deleted.deleted);
+        let page = read(&client, addr, "").await;
+        assert_eq!(vec![deleted], page.messages);
+
+        let error = send(&client, addr, &token1, &method).await.unwrap_err();
+        assert_eq!(StatusCode::NOT_FOUND, error.0);
     }
 
     #[tokio::test]
-    async fn it_fails_authentication() {
+    async fn it_restores_deleted_message() {
         let (client, addr) = crate::tests::start_client_and_server().await;
-        let mut user1 = crate::users::tests::create_user(&client, addr).await;
-        user1.password = "trying-to-hack-you".to_string();
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let (_, token2) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let restore = |token: &str| {
+            let url = format!("http://{}/messages/1/restore", addr);
+            client.post(url).bearer_auth(token).send()
+        };
 
-        let method = Method::Post(CreateMessage {
-            user: user1.clone(),
-            text: TEXT.to_string(),
+        let message1 = send(&client, addr, &token1, &create(TEXT)).await.unwrap();
+        let response = restore(&token1).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        assert_eq!("Message isn't deleted!", response.text().await.unwrap());
+
+        let method = Method::Delete(DeleteMessage {
+            message: message1.id,
         });
-        let error = send(&client, addr, &method).await.unwrap_err();
+        send(&client, addr, &token1, &method).await.unwrap();
+        let response = restore(&token2).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
 
-        assert_eq!("Password doesn't match", error);
+        let response = restore(&token1).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let restored: Message = response.json().await.unwrap();
+        assert_eq!(message1, restored);
+        let page = read(&client, addr, "").await;
+        assert_eq!(vec![message1], page.messages);
     }
 
     #[tokio::test]
-    async fn it_fails_author_validation() {
+    async fn it_reads_messages_by_page() {
         let (client, addr) = crate::tests::start_client_and_server().await;
-        let user1 = crate::users::tests::create_user(&client, addr).await;
-        let user2 = crate::users::tests::create_user(&client, addr).await;
+        let (user1, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
 
-        let method = Method::Post(CreateMessage {
-            user: user1.clone(),
-            text: TEXT.to_string(),
+        let mut messages = Vec::new();
+        for i in 0..3 {
+            let method = create(&format!("{} {}", TEXT, i));
+            messages.push(send(&client, addr, &token1, &method).await.unwrap());
+        }
+
+        let page = read(&client, addr, "limit=2").await;
+        assert_eq!(messages[1..], page.messages);
+        assert_eq!(1, page.users.len());
+        assert_eq!(user1.id, page.users[0].id);
+        let cursor = String::from(page.next_cursor.unwrap());
+
+        let page = read(&client, addr, &format!("before={}&limit=2", cursor)).await;
+        assert_eq!(messages[..1], page.messages);
+        assert!(page.next_cursor.is_none());
+
+        let cursor = String::from(Cursor::from(&messages[0]));
+        let page = read(&client, addr, &format!("after={}&limit=1", cursor)).await;
+        assert_eq!(messages[1..2], page.messages);
+        let cursor = String::from(page.next_cursor.unwrap());
+
+        let page = read(&client, addr, &format!("after={}", cursor)).await;
+        assert_eq!(messages[2..], page.messages);
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[tokio::test]
+    async fn it_reads_threads() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (user1, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let (user2, token2) = crate::users::tests::create_user_with_session(&client, addr).await;
+
+        let message1 = send(&client, addr, &token1, &create(TEXT)).await.unwrap();
+        let reply1 = send(&client, addr, &token2, &reply(message1.id, TEXT)).await;
+        let reply1 = reply1.unwrap();
+        // Replying to a reply attaches the reply to the top-level message.
+        let reply2 = send(&client, addr, &token1, &reply(reply1.id, TEXT)).await;
+        let reply2 = reply2.unwrap();
+        assert_eq!(Some(message1.id), reply1.parent);
+        assert_eq!(Some(message1.id), reply2.parent);
+
+        let page = read(&client, addr, "").await;
+        assert_eq!(1, page.messages.len());
+        assert_eq!(2, page.messages[0].reply_count);
+
+        let url = format!("http://{}/messages/{}/thread", addr, reply2.id);
+        let response = client.get(&url).send().await.unwrap();
+        let thread: Thread = response.json().await.unwrap();
+        assert_eq!(page.messages[0], thread.message);
+        assert_eq!(vec![reply1.clone(), reply2], thread.replies);
+        let mut users: Vec<_> = thread.users.iter().map(|user| user.id).collect();
+        users.sort_unstable();
+        assert_eq!(vec![user1.id, user2.id], users);
+
+        let method = Method::Delete(DeleteMessage { message: reply1.id });
+        send(&client, addr, &token2, &method).await.unwrap();
+        let page = read(&client, addr, "").await;
+        assert_eq!(1, page.messages[0].reply_count);
+
+        let method = Method::Delete(DeleteMessage {
+            message: message1.id,
         });
-        let message1 = send(&client, addr, &method).await.unwrap();
+        send(&client, addr, &token1, &method).await.unwrap();
+        let response = client.get(&url).send().await.unwrap();
+        let thread: Thread = response.json().await.unwrap();
+        // Deleted messages are tombstones, so the thread stays intact.
+        assert!(thread.message.deleted);
+        assert_eq!(1, thread.message.reply_count);
+        assert_eq!(2, thread.replies.len());
+        assert!(thread.replies[0].deleted);
+        let error = send(&client, addr, &token2, &reply(message1.id, TEXT)).await;
+        assert_eq!(StatusCode::NOT_FOUND, error.unwrap_err().0);
+    }
+
+    #[tokio::test]
+    async fn it_reacts_to_messages() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let (_, token2) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let message1 = send(&client, addr, &token1, &create(TEXT)).await.unwrap();
+
+        for (token, emoji) in [
+            (&token1, "👍"),
+            (&token2, "👍"),
+            (&token2, "👍"),
+            (&token2, "🎉"),
+        ] {
+            react(&client, addr, token, message1.id, emoji)
+                .await
+                .unwrap();
+        }
+        // Reactions within the same second are sorted by emoji code point.
+        let page = read(&client, addr, "").await;
+        let reactions = vec![
+            Reaction {
+                emoji: "🎉".to_string(),
+                count: 1,
+            },
+            Reaction {
+                emoji: "👍".to_string(),
+                count: 2,
+            },
+        ];
+        assert_eq!(reactions, page.messages[0].reactions);
+
+        let url = format!("http://{}/messages/{}/reactions/👍", addr, message1.id);
+        let response = client
+            .delete(&url)
+            .bearer_auth(&token1)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let message: Message = response.json().await.unwrap();
+        assert_eq!(1, message.reactions[1].count);
+
+        let response = client
+            .delete(&url)
+            .bearer_auth(&token1)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        assert_eq!("Reaction doesn't exist", response.text().await.unwrap());
+
+        let error = react(&client, addr, &token1, message1.id, "ok").await;
+        assert_eq!(StatusCode::BAD_REQUEST, error.unwrap_err().0);
+        let error = react(&client, addr, "trying-to-hack-you", message1.id, "👍").await;
+        assert_eq!(StatusCode::UNAUTHORIZED, error.unwrap_err().0);
+    }
+
+    #[tokio::test]
+    async fn it_searches_messages() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let (user2, token2) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let search = |query: &str| {
+            let url = format!("http://{}/messages/search?{}", addr, query);
+            client.get(url).send()
+        };
+
+        let text = "The quick brown fox";
+        let message1 = send(&client, addr, &token1, &create(text)).await.unwrap();
+        let message2 = send(&client, addr, &token2, &create("A <b>Brown</b> dog"))
+            .await
+            .unwrap();
+        send(&client, addr, &token2, &create(TEXT)).await.unwrap();
+
+        let response = search("q=brown").await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let results: SearchResults = response.json().await.unwrap();
+        assert_eq!(2, results.results.len());
+        assert_eq!(2, results.users.len());
+
+        let response = search("q=fox%20BROWN").await.unwrap();
+        let results: SearchResults = response.json().await.unwrap();
+        assert_eq!(1, results.results.len());
+        assert_eq!(message1, results.results[0].message);
+
+        let query = format!("q=brown&author={}", user2.id);
+        let response = search(&query).await.unwrap();
+        let results: SearchResults = response.json().await.unwrap();
+        assert_eq!(1, results.results.len());
+        assert_eq!(message2.id, results.results[0].message.id);
+        assert!(results.results[0].snippet.contains("<mark>Brown</mark>"));
+        assert!(!results.results[0].snippet.contains("<b>"));
+
+        let query = format!("q=brown&until={}", message1.created);
+        let response = search(&query).await.unwrap();
+        let results: SearchResults = response.json().await.unwrap();
+        assert!(results.results.is_empty());
+
+        let response = search("q=%20").await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        assert_eq!(
+            "Search query can't be empty!",
+            response.text().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn it_fails_authentication() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+
+        let error = send(&client, addr, "trying-to-hack-you", &create(TEXT))
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            (
+                StatusCode::UNAUTHORIZED,
+                "Session is invalid or expired".to_string()
+            ),
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn it_fails_author_validation() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let (_, token2) = crate::users::tests::create_user_with_session(&client, addr).await;
+
+        let message1 = send(&client, addr, &token1, &create(TEXT)).await.unwrap();
 
         let method = Method::Put(UpdateMessage {
             message: message1.id,
-            user: user2.clone(),
             text: TEXT.to_string(),
         });
-        let error = send(&client, addr, &method).await.unwrap_err();
+        let error = send(&client, addr, &token2, &method).await.unwrap_err();
 
-        assert_eq!("You're not the author!", error);
+        assert_eq!(
+            (StatusCode::FORBIDDEN, "You're not the author!".to_string()),
+            error
+        );
     }
 
     #[tokio::test]
     async fn it_fails_text_validation() {
         let (client, addr) = crate::tests::start_client_and_server().await;
-        let user1 = crate::users::tests::create_user(&client, addr).await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
 
-        let method = Method::Post(CreateMessage {
-            user: user1.clone(),
-            text: "is it okay to say fuck in here?".to_string(),
-        });
-        let error = send(&client, addr, &method).await.unwrap_err();
+        let method = create("is it okay to say fuck in here?");
+        let error = send(&client, addr, &token1, &method).await.unwrap_err();
+
+        assert_eq!(
+            (
+                StatusCode::BAD_REQUEST,
+                "No swear words please!".to_string()
+            ),
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn it_accepts_quotes_in_text() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+
+        let text = "It's a 'quoted'); DROP TABLE messages; --";
+        let message1 = send(&client, addr, &token1, &create(text)).await.unwrap();
+
+        assert_eq!(text, message1.text);
+    }
+
+    #[tokio::test]
+    async fn it_accepts_unicode_text() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+
+        // The decomposed `e` and combining accent are normalized to `é`.
+        let method = create("Cafe\u{301} 日本語 👋🏽");
+        let message1 = send(&client, addr, &token1, &method).await.unwrap();
+        assert_eq!("Caf\u{e9} 日本語 👋🏽", message1.text);
+
+        // Each family emoji is a single grapheme cluster of 5 code points.
+        let family = "👨\u{200d}👩\u{200d}👧";
+        let method = create(&family.repeat(100));
+        assert!(send(&client, addr, &token1, &method).await.is_ok());
+        let method = create(&family.repeat(101));
+        let error = send(&client, addr, &token1, &method).await.unwrap_err();
+        assert_eq!(
+            (
+                StatusCode::BAD_REQUEST,
+                "Maximum 100 characters please!".to_string()
+            ),
+            error
+        );
+
+        let method = create("Hello \u{202e}dlroW");
+        let error = send(&client, addr, &token1, &method).await.unwrap_err();
+        assert_eq!(
+            (
+                StatusCode::BAD_REQUEST,
+                "No control or bidirectional override characters please!".to_string()
+            ),
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn it_fails_missing_message() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+
+        let method = Method::Delete(DeleteMessage { message: 42 });
+        let error = send(&client, addr, &token1, &method).await.unwrap_err();
 
-        assert_eq!("No swear words please!", error);
+        assert_eq!(
+            (StatusCode::NOT_FOUND, "Message doesn't exist".to_string()),
+            error
+        );
     }
 }
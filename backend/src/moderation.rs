@@ -0,0 +1,141 @@
+//! This module is responsible for the `/moderation` endpoint.
+
+use crate::database::PolicyConfig;
+use crate::sessions::Admin;
+use crate::{wrap_error, Result, StateExt};
+use axum::routing::post;
+use axum::{Json, Router};
+
+/// This function builds and returns the router for the `/moderation` endpoint.
+pub fn make_router() -> Router {
+    Router::new().route("/reload", post(reload_policy))
+}
+
+/// This function handles the `POST /moderation/reload` requests.
+///
+/// It attempts to reload the moderation policy from its file, so that it can change
+/// without restarting the server. If successful, it returns the settings of the new
+/// policy. Otherwise, it returns an error and the former policy stays in force.
+async fn reload_policy(_: Admin, state: StateExt) -> Result<Json<PolicyConfig>> {
+    let policy = state.db.reload_policy().await.map_err(wrap_error)?;
+    Ok(Json(policy))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{CreateMessage, PolicyConfig};
+    use crate::messages::tests::{send, Method};
+    use reqwest::{Client, StatusCode};
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+
+    const POLICY: &str = r#"
+        max_length = 30
+        unicode = true
+
+        [[rules]]
+        name = "swear words"
+        words = ["shit", "ass"]
+        leet = true
+        action = "mask"
+
+        [[rules]]
+        name = "links"
+        pattern = "https?://"
+        action = "flag"
+
+        [[rules]]
+        name = "spam"
+        words = ["buy now"]
+        action = "reject"
+        message = "No spam please!"
+    "#;
+
+    fn create(text: &str) -> Method {
+        Method::Post(CreateMessage {
+            room: None,
+            reply_to: None,
+            text: text.to_string(),
+        })
+    }
+
+    async fn reload(client: &Client, addr: SocketAddr, token: Option<&str>) -> StatusCode {
+        let url = format!("http://{}/moderation/reload", addr);
+        let mut request = client.post(&url);
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        request.send().await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn it_moderates_messages_and_reloads_policy() {
+        let path: PathBuf = std::env::temp_dir().join(format!(
+            "chitchat_policy_{}_{:x}.toml",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        std::fs::write(&path, POLICY).unwrap();
+        let policy = path.to_str().unwrap();
+        let args = ["--moderation-policy", policy, "--admin-token", "secret"];
+        let (client, addr) = crate::tests::start_client_and_server_with(&args).await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+
+        let message1 = send(
+            &client,
+            addr,
+            &token1,
+            &create("what the $h1t, a class act"),
+        )
+        .await
+        .unwrap();
+        assert_eq!("what the ****, a class act", message1.text);
+        assert!(!message1.flagged);
+        let message2 = send(&client, addr, &token1, &create("see https://example.com"))
+            .await
+            .unwrap();
+        assert!(message2.flagged);
+        let message3 = send(&client, addr, &token1, &create("héllo 👋")).await;
+        assert_eq!("héllo 👋", message3.unwrap().text);
+        let error = send(&client, addr, &token1, &create("BUY NOW!")).await;
+        assert_eq!(
+            (StatusCode::BAD_REQUEST, "No spam please!".to_string()),
+            error.unwrap_err()
+        );
+        let error = send(&client, addr, &token1, &create(&"a".repeat(31))).await;
+        assert_eq!(StatusCode::BAD_REQUEST, error.unwrap_err().0);
+
+        std::fs::write(
+            &path,
+            "unicode = false\n[[rules]]\nname = \"hi\"\nwords = [\"hi\"]\naction = \"reject\"",
+        )
+        .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, reload(&client, addr, None).await);
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            reload(&client, addr, Some("guess")).await
+        );
+        let url = format!("http://{}/moderation/reload", addr);
+        let response = client
+            .post(&url)
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let config: PolicyConfig = response.json().await.unwrap();
+        assert_eq!(1, config.rules.len());
+
+        let error = send(&client, addr, &token1, &create("hi there")).await;
+        assert_eq!(StatusCode::BAD_REQUEST, error.unwrap_err().0);
+        let error = send(&client, addr, &token1, &create("héllo")).await;
+        assert_eq!(
+            (
+                StatusCode::BAD_REQUEST,
+                "ASCII characters only please!".to_string()
+            ),
+            error.unwrap_err()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
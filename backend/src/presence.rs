@@ -0,0 +1,188 @@
+//! This module is responsible for the `/presence` endpoint.
+//!
+//! A user is online while at least one of their authenticated websocket
+//! connections is open, so that several tabs count as one user. When a user's
+//! first connection opens, a `user_joined` event is broadcast to all clients, and
+//! when their last connection closes, a `user_left` event is broadcast after a
+//! grace period, unless the user reconnected in the meantime, e.g. after a network
+//! hiccup or a page reload.
+
+use crate::database::{Id, Profile};
+use crate::websocket::{broadcast_message, ChatEvent};
+use crate::{wrap_error, Result, StateExt};
+use axum::routing::get;
+use axum::{Json, Router};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// This struct holds the open websocket connections of an online user.
+struct Connections {
+    /// The number of open connections, which is zero during the grace period.
+    sockets: usize,
+    /// The number of times the user's last connection closed, so that a grace
+    /// period is ignored if the user reconnected and disconnected again since.
+    generation: u64,
+}
+
+/// This struct tracks the online users, i.e. the users with open websocket
+/// connections or in their grace period.
+#[derive(Default)]
+pub struct Presence {
+    users: Mutex<HashMap<Id, Connections>>,
+}
+
+impl Presence {
+    /// This method returns the IDs of the online users, in ascending order.
+    pub fn online(&self) -> Vec<Id> {
+        let mut users: Vec<Id> = self.users.lock().keys().copied().collect();
+        users.sort_unstable();
+        users
+    }
+
+    /// This method counts a new connection of the user, and returns whether the user
+    /// just came online, i.e. wasn't online nor in their grace period.
+    fn connect(&self, user: Id) -> bool {
+        let mut users = self.users.lock();
+        match users.get_mut(&user) {
+            Some(connections) => {
+                connections.sockets += 1;
+                false
+            }
+            None => {
+                let connections = Connections {
+                    sockets: 1,
+                    generation: 0,
+                };
+                users.insert(user, connections);
+                true
+            }
+        }
+    }
+
+    /// This method counts a closed connection of the user. If it was their last
+    /// connection, it returns the generation of the grace period that starts.
+    fn disconnect(&self, user: Id) -> Option<u64> {
+        let mut users = self.users.lock();
+        let connections = users.get_mut(&user)?;
+        connections.sockets -= 1;
+        if connections.sockets > 0 {
+            return None;
+        }
+        connections.generation += 1;
+        Some(connections.generation)
+    }
+
+    /// This method removes the user at the end of the grace period of the input
+    /// generation, and returns whether the user went offline, i.e. didn't reconnect.
+    fn expire(&self, user: Id, generation: u64) -> bool {
+        let mut users = self.users.lock();
+        match users.get(&user) {
+            Some(c) if c.sockets == 0 && c.generation == generation => {
+                users.remove(&user);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// This function builds and returns the router for the `/presence` endpoint.
+pub fn make_router() -> Router {
+    Router::new().route("/", get(read_presence))
+}
+
+/// This function handles the `GET /presence` requests.
+///
+/// It retrieves and returns the profiles of the online users, sorted by name.
+async fn read_presence(state: StateExt) -> Result<Json<Vec<Profile>>> {
+    let online = state.presence.online();
+    let mut profiles = state.db.read_users(&online).await.map_err(wrap_error)?;
+    profiles.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Json(profiles))
+}
+
+/// This function counts a new websocket connection of the user, and broadcasts a
+/// `user_joined` event if the user just came online.
+pub async fn connect(user: Id, state: &StateExt) {
+    if state.presence.connect(user) {
+        tracing::info!("user {} is online", user);
+        broadcast_message(ChatEvent::UserJoined { user }, state).await;
+    }
+}
+
+/// This function counts a closed websocket connection of the user and, if it was
+/// their last connection, broadcasts a `user_left` event at the end of the grace
+/// period, unless the user reconnected in the meantime.
+pub fn disconnect(user: Id, state: &StateExt) {
+    let generation = match state.presence.disconnect(user) {
+        Some(generation) => generation,
+        None => return,
+    };
+    let grace_period = Duration::from_secs(state.config.presence_grace_period);
+    let state = state.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(grace_period).await;
+        if state.presence.expire(user, generation) {
+            tracing::info!("user {} is offline", user);
+            broadcast_message(ChatEvent::UserLeft { user }, &state).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{CreateMessage, Profile};
+    use crate::messages::tests::{send, Method};
+    use crate::websocket::tests::{connect, connect_to, next_event};
+    use crate::websocket::ChatEvent;
+    use reqwest::Client;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    async fn read_presence(client: &Client, addr: SocketAddr) -> Vec<Profile> {
+        let url = format!("http://{}/presence", addr);
+        let response = client.get(&url).send().await.unwrap();
+        response.json().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_tracks_online_users() {
+        let args = ["--presence-grace-period", "1"];
+        let (client, addr) = crate::tests::start_client_and_server_with(&args).await;
+        let (user1, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let mut observer = connect(addr).await;
+        assert!(read_presence(&client, addr).await.is_empty());
+
+        // Several tabs of the same user count once.
+        let query = format!("token={}", token1);
+        let mut tab1 = connect_to(addr, &query).await;
+        let mut tab2 = connect_to(addr, &query).await;
+        let event = ChatEvent::UserJoined { user: user1.id };
+        assert_eq!(event, next_event(&mut observer).await);
+        let online = read_presence(&client, addr).await;
+        assert_eq!(
+            vec![user1.id],
+            online.iter().map(|p| p.id).collect::<Vec<_>>()
+        );
+
+        // Reconnecting within the grace period doesn't broadcast anything.
+        tab1.close(None).await.unwrap();
+        tab2.close(None).await.unwrap();
+        let mut tab3 = connect_to(addr, &query).await;
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let create = Method::Post(CreateMessage {
+            room: None,
+            reply_to: None,
+            text: "Still here".to_string(),
+        });
+        let message = send(&client, addr, &token1, &create).await.unwrap();
+        assert_eq!(ChatEvent::Created(message), next_event(&mut observer).await);
+        assert_eq!(1, read_presence(&client, addr).await.len());
+
+        tab3.close(None).await.unwrap();
+        let event = ChatEvent::UserLeft { user: user1.id };
+        assert_eq!(event, next_event(&mut observer).await);
+        assert!(read_presence(&client, addr).await.is_empty());
+    }
+}
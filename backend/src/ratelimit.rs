@@ -0,0 +1,385 @@
+//! This module is responsible for rate limiting the requests of each client.
+//!
+//! Each configured route gets a token bucket per client, i.e. per authenticated
+//! user or, for anonymous requests, per IP address. A bucket holds up to `requests`
+//! tokens and is refilled continuously at `requests` tokens per `period`, so that
+//! clients can burst but not exceed the average rate. The buckets of idle clients
+//! are full, so they're dropped periodically in the background.
+//!
+//! The user authenticated to pick the bucket of a request is added to the request
+//! extensions, so that the [`AuthUser`] extractor doesn't authenticate it again.
+
+use crate::database::Id;
+use crate::sessions::AuthUser;
+use crate::State;
+use axum::extract::ConnectInfo;
+use axum::http::header::{AUTHORIZATION, RETRY_AFTER};
+use axum::http::{Method, Request, StatusCode};
+use axum::response::{Headers, IntoResponse, Response};
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{Layer, Service};
+
+/// This constant is the interval at which the full buckets, i.e. those of the
+/// clients that have been idle for long enough, are dropped.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// This struct represents the rate limit of a route, e.g. `POST /messages=10/60`
+/// for at most 10 requests per 60 seconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteLimit {
+    pub method: Method,
+    /// The path of the route, where segments starting with `:` match any segment,
+    /// e.g. `/messages/:id/reactions`.
+    pub path: String,
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl RouteLimit {
+    /// This method returns whether the route matches the method and path.
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        let pattern = self.path.trim_end_matches('/').split('/');
+        let path = path.trim_end_matches('/').split('/');
+        self.method == method
+            && pattern.clone().count() == path.clone().count()
+            && pattern
+                .zip(path)
+                .all(|(pattern, segment)| pattern.starts_with(':') || pattern == segment)
+    }
+}
+
+impl FromStr for RouteLimit {
+    type Err = String;
+
+    fn from_str(limit: &str) -> Result<Self, Self::Err> {
+        let error = || {
+            format!(
+                "Invalid rate limit, expected e.g. `POST /messages=10/60`: {}",
+                limit
+            )
+        };
+        let (route, rate) = limit.split_once('=').ok_or_else(error)?;
+        let (method, path) = route.trim().split_once(' ').ok_or_else(error)?;
+        let (requests, period) = rate.trim().split_once('/').ok_or_else(error)?;
+        let method = Method::from_str(method).map_err(|_| error())?;
+        let requests = requests.parse().map_err(|_| error())?;
+        let period = period.parse().map_err(|_| error())?;
+        if requests == 0 || period == 0 || !path.trim().starts_with('/') {
+            return Err(error());
+        }
+        Ok(Self {
+            method,
+            path: path.trim().to_string(),
+            requests,
+            period: Duration::from_secs(period),
+        })
+    }
+}
+
+/// This enum represents the client a bucket belongs to.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Client {
+    User(Id),
+    Ip(IpAddr),
+}
+
+/// This struct holds the tokens left to a client for a route.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// This struct holds the rate limits of all routes, and the buckets of all clients.
+pub struct RateLimiter {
+    limits: Vec<RouteLimit>,
+    /// The buckets indexed by route limit, i.e. by index in `limits`, and client.
+    buckets: Mutex<HashMap<(usize, Client), Bucket>>,
+}
+
+impl RateLimiter {
+    /// This constructor initializes a rate limiter without any bucket.
+    pub fn new(limits: Vec<RouteLimit>) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// This method returns whether requests to the route are rate limited.
+    pub fn is_limited(&self, method: &Method, path: &str) -> bool {
+        self.limits.iter().any(|limit| limit.matches(method, path))
+    }
+
+    /// This method takes a token from the bucket of the client for the route, if
+    /// it's rate limited. If the bucket is empty, it returns how long the client
+    /// must wait for the next token.
+    pub fn check(&self, method: &Method, path: &str, client: Client) -> Result<(), Duration> {
+        let index = match self.limits.iter().position(|l| l.matches(method, path)) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let limit = &self.limits[index];
+        let capacity = f64::from(limit.requests);
+        let rate = capacity / limit.period.as_secs_f64();
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        let bucket = buckets.entry((index, client)).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = capacity.min(bucket.tokens + elapsed * rate);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// This method drops the buckets that have been idle for at least the period of
+    /// their route, since they're full again, and returns how many were dropped.
+    pub fn evict_idle_buckets(&self) -> usize {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        let count = buckets.len();
+        let limits = &self.limits;
+        buckets.retain(|(index, _), bucket| {
+            now.duration_since(bucket.updated) < limits[*index].period
+        });
+        count - buckets.len()
+    }
+}
+
+/// This function runs in the background for the entire duration of the application,
+/// and periodically drops the buckets of the idle clients.
+pub async fn evict_idle_buckets(state: Arc<State>) {
+    let mut interval = tokio::time::interval(EVICTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        match state.limiter.evict_idle_buckets() {
+            0 => {}
+            count => tracing::info!("dropped {} idle rate limit buckets", count),
+        }
+    }
+}
+
+/// This struct is the layer applying the rate limits of the global state to all
+/// routes. It must be added after the global state extension.
+#[derive(Clone)]
+pub struct RateLimitLayer;
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService { inner }
+    }
+}
+
+/// This struct is the service rejecting the requests of the clients exceeding the
+/// rate limits with `429 Too Many Requests`, before they reach the inner service.
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+}
+
+impl<S, B> Service<Request<B>> for RateLimitService<S>
+where
+    S: Service<Request<B>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        // The inner service is ready, but its clone may not be: swap them.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let check = RateCheck::new(&req);
+        Box::pin(async move {
+            if let Some(check) = check {
+                match check.run().await {
+                    Ok(Some(user)) => drop(req.extensions_mut().insert(user)),
+                    Ok(None) => {}
+                    Err(response) => return Ok(response),
+                }
+            }
+            inner.call(req).await
+        })
+    }
+}
+
+/// This struct holds what's needed to apply the rate limit of a route to the client
+/// of a request, so that the request isn't borrowed while authenticating the client.
+struct RateCheck {
+    state: Arc<State>,
+    method: Method,
+    path: String,
+    token: Option<String>,
+    ip: Option<IpAddr>,
+}
+
+impl RateCheck {
+    /// This constructor returns the rate check of the request, if its route is
+    /// rate limited.
+    fn new<B>(req: &Request<B>) -> Option<Self> {
+        let state = req.extensions().get::<Arc<State>>()?;
+        if !state.limiter.is_limited(req.method(), req.uri().path()) {
+            return None;
+        }
+        let token = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        let ip = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        Some(Self {
+            state: state.clone(),
+            method: req.method().clone(),
+            path: req.uri().path().to_string(),
+            token,
+            ip,
+        })
+    }
+
+    /// This method takes a token from the bucket of the client, i.e. of the user if
+    /// the request is authenticated or of the IP address otherwise, and returns the
+    /// authenticated user if any, or the `429 Too Many Requests` response if the
+    /// bucket is empty.
+    async fn run(self) -> Result<Option<AuthUser>, Response> {
+        let user = match self.token {
+            Some(token) => match self.state.db.authenticate_token(&token).await {
+                Ok(id) => Some(AuthUser { id, token }),
+                Err(_) => None,
+            },
+            None => None,
+        };
+        let client = match (&user, self.ip) {
+            (Some(user), _) => Client::User(user.id),
+            (None, Some(ip)) => Client::Ip(ip),
+            (None, None) => return Ok(None),
+        };
+        let limiter = &self.state.limiter;
+        if let Err(wait) = limiter.check(&self.method, &self.path, client) {
+            tracing::warn!(
+                "rate limit exceeded by {:?} on {} {}",
+                client,
+                self.method,
+                self.path
+            );
+            return Err(too_many_requests(wait));
+        }
+        Ok(user)
+    }
+}
+
+/// This function returns the `429 Too Many Requests` response asking the client to
+/// wait for the input duration, rounded up to the second.
+pub fn too_many_requests(wait: Duration) -> Response {
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let headers = Headers([(RETRY_AFTER, seconds.to_string())]);
+    let (status, error) = rate_limit_error(wait);
+    (status, headers, error).into_response()
+}
+
+/// This function returns the error asking the client to wait for the input duration,
+/// rounded up to the second, e.g. for clients that can't read the `Retry-After` header.
+pub fn rate_limit_error(wait: Duration) -> (StatusCode, String) {
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let error = format!("Too many requests, retry in {} seconds", seconds);
+    (StatusCode::TOO_MANY_REQUESTS, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Client, RateLimiter, RouteLimit};
+    use axum::http::Method;
+    use reqwest::header::RETRY_AFTER;
+    use reqwest::StatusCode;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+
+    #[test]
+    fn it_parses_route_limits() {
+        let limit: RouteLimit = "POST /messages/:id/reactions=10/60".parse().unwrap();
+        assert_eq!(Method::POST, limit.method);
+        assert_eq!(10, limit.requests);
+        assert_eq!(Duration::from_secs(60), limit.period);
+        assert!(limit.matches(&Method::POST, "/messages/42/reactions"));
+        assert!(!limit.matches(&Method::POST, "/messages/42"));
+        assert!(!limit.matches(&Method::DELETE, "/messages/42/reactions"));
+
+        assert!("POST /messages".parse::<RouteLimit>().is_err());
+        assert!("POST /messages=0/60".parse::<RouteLimit>().is_err());
+        assert!("/messages=10/60".parse::<RouteLimit>().is_err());
+    }
+
+    #[test]
+    fn it_limits_each_client() {
+        let limiter = RateLimiter::new(vec!["POST /users=2/60".parse().unwrap()]);
+        let ip = Client::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        assert!(limiter.check(&Method::POST, "/users", ip).is_ok());
+        assert!(limiter.check(&Method::POST, "/users", ip).is_ok());
+        let wait = limiter.check(&Method::POST, "/users", ip).unwrap_err();
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+
+        assert!(limiter
+            .check(&Method::POST, "/users", Client::User(1))
+            .is_ok());
+        assert!(limiter.check(&Method::GET, "/users", ip).is_ok());
+    }
+
+    #[test]
+    fn it_evicts_idle_buckets() {
+        let mut limit: RouteLimit = "POST /users=2/60".parse().unwrap();
+        limit.period = Duration::from_millis(10);
+        let limiter = RateLimiter::new(vec![limit]);
+        let ip = Client::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        assert!(limiter.check(&Method::POST, "/users", ip).is_ok());
+        assert_eq!(0, limiter.evict_idle_buckets());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(1, limiter.evict_idle_buckets());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_too_many_requests() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let url = format!("http://{}/users", addr);
+        let params = serde_json::json!({ "name": "" });
+
+        // Invalid requests count too, since they still hit the database.
+        for _ in 0..5 {
+            let response = client.post(&url).json(&params).send().await.unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        }
+        let response = client.post(&url).json(&params).send().await.unwrap();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        let retry_after: u64 = response.headers()[RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(720, retry_after);
+    }
+}
@@ -0,0 +1,127 @@
+//! This module is responsible for the `/rooms` endpoint.
+
+use crate::database::{CreateRoom, Id, MessagePage, ReadMessages, Room};
+use crate::sessions::AuthUser;
+use crate::{wrap_error, Result, StateExt};
+use axum::extract::{Path, Query};
+use axum::routing::get;
+use axum::{Json, Router};
+
+/// This function builds and returns the router for the `/rooms` endpoint.
+pub fn make_router() -> Router {
+    Router::new()
+        .route("/", get(read_rooms).post(create_room))
+        .route("/:id/messages", get(read_room_messages))
+}
+
+/// This function handles the `GET /rooms` requests.
+///
+/// It retrieves and returns all rooms sorted by name.
+async fn read_rooms(state: StateExt) -> Result<Json<Vec<Room>>> {
+    let all_rooms = state.db.read_rooms().await.map_err(wrap_error)?;
+    Ok(Json(all_rooms))
+}
+
+/// This function handles the `POST /rooms` requests.
+///
+/// It attempts to create a new room. If successful, it returns the created room.
+/// Otherwise, it returns an error.
+async fn create_room(_: AuthUser, params: Json<CreateRoom>, state: StateExt) -> Result<Json<Room>> {
+    let created_room = state.db.create_room(params.0).await.map_err(wrap_error)?;
+    Ok(Json(created_room))
+}
+
+/// This function handles the `GET /rooms/:id/messages` requests.
+///
+/// It retrieves and returns a page of messages posted in the room, in the same way
+/// as the `GET /messages` requests.
+async fn read_room_messages(
+    Path(id): Path<Id>,
+    params: Query<ReadMessages>,
+    state: StateExt,
+) -> Result<Json<MessagePage>> {
+    let params = ReadMessages {
+        room: Some(id),
+        ..params.0
+    };
+    let page = state.db.read_messages(params).await.map_err(wrap_error)?;
+    Ok(Json(page))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::database::{CreateMessage, CreateRoom, MessagePage, Room};
+    use crate::messages::tests::{send, Method};
+    use reqwest::{Client, StatusCode};
+    use std::net::SocketAddr;
+
+    pub async fn create_room(client: &Client, addr: SocketAddr, token: &str, name: &str) -> Room {
+        let url = format!("http://{}/rooms", addr);
+        let params = CreateRoom {
+            name: name.to_string(),
+        };
+        let request = client.post(url).json(&params).bearer_auth(token);
+        let response = request.send().await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        response.json().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_creates_rooms() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+
+        let room1 = create_room(&client, addr, &token1, "room1").await;
+        let room2 = create_room(&client, addr, &token1, "room2").await;
+
+        let url = format!("http://{}/rooms", addr);
+        let response = client.get(url).send().await.unwrap();
+        let rooms: Vec<Room> = response.json().await.unwrap();
+        let names: Vec<_> = rooms.iter().map(|room| room.name.as_str()).collect();
+
+        assert_eq!(vec!["general", "room1", "room2"], names);
+        assert_eq!(vec![room1, room2], rooms[1..]);
+    }
+
+    #[tokio::test]
+    async fn it_fails_duplicate_room() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+
+        let url = format!("http://{}/rooms", addr);
+        let params = CreateRoom {
+            name: "general".to_string(),
+        };
+        let request = client.post(url).json(&params).bearer_auth(&token1);
+        let response = request.send().await.unwrap();
+
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        assert_eq!("Room already exists", response.text().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn it_reads_room_messages() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let room1 = create_room(&client, addr, &token1, "room1").await;
+
+        let mut messages = Vec::new();
+        for room in [None, Some(room1.id)] {
+            let method = Method::Post(CreateMessage {
+                room,
+                reply_to: None,
+                text: "Hello, World!".to_string(),
+            });
+            messages.push(send(&client, addr, &token1, &method).await.unwrap());
+        }
+
+        let url = format!("http://{}/rooms/{}/messages", addr, room1.id);
+        let response = client.get(url).send().await.unwrap();
+        let page: MessagePage = response.json().await.unwrap();
+        assert_eq!(messages[1..], page.messages);
+
+        let url = format!("http://{}/rooms/{}/messages", addr, i32::MAX);
+        let response = client.get(url).send().await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+}
@@ -0,0 +1,206 @@
+//! This module is responsible for the `/sessions` endpoint and for authenticating
+//! requests with the session tokens it issues.
+
+use crate::database::{Id, Session, User};
+use crate::{wrap_error, Result, State, StateExt};
+use axum::async_trait;
+use axum::extract::{Extension, FromRequest, RequestParts};
+use axum::http::header::AUTHORIZATION;
+use axum::http::StatusCode;
+use axum::routing::post;
+use axum::{Json, Router};
+use std::sync::Arc;
+
+/// This struct is an extractor for route handlers that require authentication.
+///
+/// It validates the bearer token in the `Authorization` header of the request
+/// and yields the ID of the user who opened the session. Otherwise, the request
+/// is rejected with a 401. If the rate limiter already validated the token, the
+/// user it found in the request extensions is reused.
+#[derive(Clone)]
+pub struct AuthUser {
+    pub id: Id,
+    pub token: String,
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for AuthUser {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self> {
+        let extensions = req.extensions();
+        if let Some(user) = extensions.and_then(|extensions| extensions.get::<AuthUser>()) {
+            return Ok(user.clone());
+        }
+        let token = bearer_token(req)?;
+        let state = global_state(req).await?;
+        let id = state
+            .db
+            .authenticate_token(&token)
+            .await
+            .map_err(wrap_error)?;
+        Ok(Self { id, token })
+    }
+}
+
+/// This struct is an extractor for route handlers that also serve anonymous requests.
+///
+/// It yields the authenticated user if the request has an `Authorization` header,
+/// and `None` otherwise. Invalid tokens are still rejected with a 401.
+pub struct MaybeAuthUser(pub Option<AuthUser>);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for MaybeAuthUser {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self> {
+        let headers = req.headers();
+        if headers.filter(|h| h.contains_key(AUTHORIZATION)).is_none() {
+            return Ok(Self(None));
+        }
+        let user = AuthUser::from_request(req).await?;
+        Ok(Self(Some(user)))
+    }
+}
+
+/// This struct is an extractor for route handlers restricted to administrators.
+///
+/// It validates that the bearer token in the `Authorization` header of the request
+/// is the configured admin token. Otherwise, the request is rejected with a 401 if
+/// the token is missing, or with a 403.
+pub struct Admin;
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Admin {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self> {
+        let token = bearer_token(req)?;
+        let state = global_state(req).await?;
+        let admin_token = match &state.config.admin_token {
+            Some(admin_token) => admin_token,
+            None => {
+                let error = "Admin endpoints are disabled".to_string();
+                return Err((StatusCode::FORBIDDEN, error));
+            }
+        };
+        // Compare in constant time, so that the admin token can't be guessed from
+        // how long the comparison takes.
+        let diff = token
+            .bytes()
+            .zip(admin_token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        if diff != 0 || token.len() != admin_token.len() {
+            let error = "You're not an admin!".to_string();
+            return Err((StatusCode::FORBIDDEN, error));
+        }
+        Ok(Self)
+    }
+}
+
+/// This function returns the bearer token in the `Authorization` header of the
+/// request, or rejects the request with a 401.
+fn bearer_token<B>(req: &RequestParts<B>) -> Result<String> {
+    req.headers()
+        .and_then(|headers| headers.get(AUTHORIZATION))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+        .ok_or_else(|| {
+            let error = "Missing bearer token".to_string();
+            (StatusCode::UNAUTHORIZED, error)
+        })
+}
+
+/// This function returns the global state, which is an extension of all requests.
+async fn global_state<B: Send>(req: &mut RequestParts<B>) -> Result<Arc<State>> {
+    let Extension(state) = Extension::<Arc<State>>::from_request(req)
+        .await
+        .map_err(|_| {
+            let error = "Missing global state".to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, error)
+        })?;
+    Ok(state)
+}
+
+/// This function builds and returns the router for the `/sessions` endpoint.
+pub fn make_router() -> Router {
+    Router::new().route("/", post(create_session).delete(delete_session))
+}
+
+/// This function handles the `POST /sessions` requests.
+///
+/// It attempts to open a new session with the user credentials. If successful,
+/// it returns the session with its bearer token. Otherwise, it returns an error.
+async fn create_session(params: Json<User>, state: StateExt) -> Result<Json<Session>> {
+    let session = state
+        .db
+        .create_session(params.0)
+        .await
+        .map_err(wrap_error)?;
+    Ok(Json(session))
+}
+
+/// This function handles the `DELETE /sessions` requests.
+///
+/// It closes the session used to authenticate the request.
+async fn delete_session(user: AuthUser, state: StateExt) -> Result<()> {
+    state
+        .db
+        .delete_session(&user.token)
+        .await
+        .map_err(wrap_error)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::database::{Session, User};
+    use reqwest::{Client, StatusCode};
+    use std::net::SocketAddr;
+
+    pub async fn create_session(client: &Client, addr: SocketAddr, user: &User) -> Session {
+        let url = format!("http://{}/sessions", addr);
+        let response = client.post(url).json(user).send().await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        response.json().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_creates_and_deletes_session() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let user1 = crate::users::tests::create_user(&client, addr).await;
+
+        let session1 = create_session(&client, addr, &user1).await;
+        assert_eq!(user1.id, session1.user);
+
+        let url = format!("http://{}/sessions", addr);
+        let response = client
+            .delete(&url)
+            .bearer_auth(&session1.token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        let response = client
+            .delete(&url)
+            .bearer_auth(&session1.token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+
+    #[tokio::test]
+    async fn it_fails_authentication() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let mut user1 = crate::users::tests::create_user(&client, addr).await;
+        user1.password = "trying-to-hack-you".to_string();
+
+        let url = format!("http://{}/sessions", addr);
+        let response = client.post(url).json(&user1).send().await.unwrap();
+
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert_eq!("Password doesn't match", response.text().await.unwrap());
+    }
+}
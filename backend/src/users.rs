@@ -1,34 +1,88 @@
 //! This module is responsible for the `/users` endpoint.
 
-use crate::database::User;
-use crate::{wrap_400, Result, StateExt};
-use axum::routing::post;
+use crate::database::{CreateUser, Id, Profile, UpdateUser, User};
+use crate::sessions::AuthUser;
+use crate::{wrap_error, Result, StateExt};
+use axum::extract::Path;
+use axum::routing::{get, post};
 use axum::{Json, Router};
 
 /// This function builds and returns the router for the `/users` endpoint.
 pub fn make_router() -> Router {
-    Router::new().route("/", post(create_user))
+    Router::new()
+        .route("/", post(create_user))
+        .route("/:id", get(read_user).patch(update_user))
 }
 
 /// This function handles the `POST /users` requests.
 ///
-/// It creates and returns a new user with a unique ID and a random password.
-async fn create_user(state: StateExt) -> Result<Json<User>> {
-    let created_user = state.db.create_user().await.map_err(wrap_400)?;
+/// It attempts to create a new user with a unique ID, a random password, and the
+/// chosen profile. If successful, it returns the user credentials. Otherwise, it
+/// returns an error.
+async fn create_user(params: Json<CreateUser>, state: StateExt) -> Result<Json<User>> {
+    let created_user = state.db.create_user(params.0).await.map_err(wrap_error)?;
     Ok(Json(created_user))
 }
 
+/// This function handles the `GET /users/:id` requests.
+///
+/// It retrieves and returns the profile of the user.
+async fn read_user(Path(id): Path<Id>, state: StateExt) -> Result<Json<Profile>> {
+    let profile = state.db.read_user(id).await.map_err(wrap_error)?;
+    Ok(Json(profile))
+}
+
+/// This function handles the `PATCH /users/:id` requests.
+///
+/// It attempts to update the profile of the authenticated user. If successful,
+/// it returns the updated profile. Otherwise, it returns an error.
+async fn update_user(
+    user: AuthUser,
+    Path(id): Path<Id>,
+    params: Json<UpdateUser>,
+    state: StateExt,
+) -> Result<Json<Profile>> {
+    let profile = state
+        .db
+        .update_user(user.id, id, params.0)
+        .await
+        .map_err(wrap_error)?;
+    Ok(Json(profile))
+}
+
 #[cfg(test)]
 pub mod tests {
-    use crate::database::User;
+    use crate::database::{CreateUser, Profile, UpdateUser, User};
     use reqwest::{Client, StatusCode};
     use std::net::SocketAddr;
 
     pub async fn create_user(client: &Client, addr: SocketAddr) -> User {
+        let name = format!("user-{:x}", rand::random::<u64>());
+        create_named_user(client, addr, &name).await.unwrap()
+    }
+
+    pub async fn create_named_user(
+        client: &Client,
+        addr: SocketAddr,
+        name: &str,
+    ) -> Result<User, (StatusCode, String)> {
         let url = format!("http://{}/users", addr);
-        let response = client.post(url).send().await.unwrap();
-        assert_eq!(StatusCode::OK, response.status());
-        response.json().await.unwrap()
+        let params = CreateUser {
+            name: name.to_string(),
+            color: None,
+            bio: None,
+        };
+        let response = client.post(url).json(&params).send().await.unwrap();
+        match response.status() {
+            StatusCode::OK => Ok(response.json().await.unwrap()),
+            status => Err((status, response.text().await.unwrap())),
+        }
+    }
+
+    pub async fn create_user_with_session(client: &Client, addr: SocketAddr) -> (User, String) {
+        let user = create_user(client, addr).await;
+        let session = crate::sessions::tests::create_session(client, addr, &user).await;
+        (user, session.token)
     }
 
     #[tokio::test]
@@ -40,4 +94,48 @@ pub mod tests {
 
         assert!(user1.id < user2.id);
     }
+
+    #[tokio::test]
+    async fn it_fails_duplicate_name() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+
+        create_named_user(&client, addr, "alice").await.unwrap();
+        let error = create_named_user(&client, addr, "Alice").await.unwrap_err();
+
+        assert_eq!(
+            (StatusCode::BAD_REQUEST, "Name is already taken".to_string()),
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn it_reads_and_updates_profile() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (user1, token1) = create_user_with_session(&client, addr).await;
+        let (_, token2) = create_user_with_session(&client, addr).await;
+        let url = format!("http://{}/users/{}", addr, user1.id);
+
+        let params = UpdateUser {
+            name: Some("alice".to_string()),
+            color: Some("#ff8800".to_string()),
+            ..UpdateUser::default()
+        };
+        let request = client.patch(&url).json(&params).bearer_auth(&token2);
+        let response = request.send().await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+        let request = client.patch(&url).json(&params).bearer_auth(&token1);
+        let response = request.send().await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        let response = client.get(&url).send().await.unwrap();
+        let profile: Profile = response.json().await.unwrap();
+        let expected = Profile {
+            id: user1.id,
+            name: "alice".to_string(),
+            color: Some("#ff8800".to_string()),
+            bio: None,
+        };
+        assert_eq!(expected, profile);
+    }
 }
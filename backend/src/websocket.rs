@@ -1,12 +1,247 @@
 //! This module is responsible for the `/websocket` endpoint.
+//!
+//! Besides broadcasting events, the websocket accepts JSON commands from clients
+//! authenticated at connect time, e.g. `{"id":1,"type":"send","text":"Hello"}`.
+//! Each command gets a reply echoing its ID: an `ack` with the affected message if
+//! any, an `error` with the HTTP status the REST API would have returned, or a
+//! `pong` for `ping` commands.
+//!
+//! The `typing` command tells the other clients that the user is typing. These
+//! indicators are throttled per user and room, never logged, and expire on their
+//! own, so that clients don't need to be told when the user stops typing.
+//!
+//! The server pings clients regularly, and closes the connection with the code
+//! 1001 (going away) if a client doesn't answer in time, or once the connection
+//! has lasted for the configured lifetime.
+//!
+//! Events are logged with a sequence number, so that a client reconnecting with
+//! `?since=<seq>`, the sequence number of the last event it received, first gets
+//! the events it missed, then the live events.
 
-use crate::database::Message as ChitChatMessage;
-use crate::StateExt;
-use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use crate::database::{
+    CreateMessage, DeleteMessage, Id, LoggedEvent, Message as ChitChatMessage, Seq, UpdateMessage,
+    DEFAULT_ROOM,
+};
+use crate::ratelimit::{rate_limit_error, Client};
+use crate::sessions::MaybeAuthUser;
+use crate::{messages, presence, wrap_error, Result, State, StateExt};
+use axum::extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::Query;
+use axum::http::{Method, StatusCode};
 use axum::response::IntoResponse;
 use axum::routing::get;
 use axum::Router;
-use futures::{SinkExt, StreamExt};
+use futures::stream::{BoxStream, SelectAll};
+use futures::{Stream, StreamExt};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio::time::Instant;
+
+/// This constant is the close code sent to the websocket clients that are
+/// disconnected by the server, i.e. 1001 (going away).
+const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// This constant is how long the server waits for a websocket client to answer
+/// its close frame, before dropping the connection anyway.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// This constant is the interval at which the typing indicators that don't throttle
+/// anymore are dropped.
+const TYPING_EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// This constant is the number of logged events read at once when replaying them.
+const REPLAY_BATCH: i64 = 100;
+
+/// This enum represents an event broadcast to all connected websocket clients.
+///
+/// It is serialized with a `type` field so that clients can tell events apart
+/// and apply them incrementally, e.g. `{"type":"deleted","id":42}`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum ChatEvent {
+    /// A message has been created.
+    Created(ChitChatMessage),
+    /// A message has been updated.
+    Updated(ChitChatMessage),
+    /// A message has been deleted, and is now a tombstone.
+    Deleted { id: Id, room: Id },
+    /// A deleted message has been restored by its author.
+    Restored(ChitChatMessage),
+    /// A reply has been created or deleted in the thread of a top-level message.
+    ThreadUpdated { id: Id, room: Id, reply_count: i32 },
+    /// A user has reacted to a message, which now has `count` reactions with the emoji.
+    ReactionAdded {
+        id: Id,
+        room: Id,
+        user: Id,
+        emoji: String,
+        count: i64,
+    },
+    /// A user has removed their reaction to a message, which now has `count`
+    /// reactions with the emoji.
+    ReactionRemoved {
+        id: Id,
+        room: Id,
+        user: Id,
+        emoji: String,
+        count: i64,
+    },
+    /// A user is typing a message in a room. Clients should hide the indicator
+    /// after `expires_in` seconds, unless the user is still typing by then.
+    UserTyping { room: Id, user: Id, expires_in: u64 },
+    /// A user opened their first websocket connection, and is now online.
+    UserJoined { user: Id },
+    /// A user closed their last websocket connection, and is now offline.
+    UserLeft { user: Id },
+}
+
+impl ChatEvent {
+    /// This method returns whether the event is logged, i.e. isn't ephemeral like
+    /// typing or presence, which are pointless to replay.
+    pub fn is_logged(&self) -> bool {
+        !matches!(
+            self,
+            ChatEvent::UserTyping { .. }
+                | ChatEvent::UserJoined { .. }
+                | ChatEvent::UserLeft { .. }
+        )
+    }
+
+    /// This method returns the ID of the room in which the event occurred, or
+    /// `None` if it concerns all rooms, e.g. a user coming online.
+    pub fn room(&self) -> Option<Id> {
+        match self {
+            ChatEvent::Created(message)
+            | ChatEvent::Updated(message)
+            | ChatEvent::Restored(message) => Some(message.room),
+            ChatEvent::Deleted { room, .. }
+            | ChatEvent::ThreadUpdated { room, .. }
+            | ChatEvent::ReactionAdded { room, .. }
+            | ChatEvent::ReactionRemoved { room, .. }
+            | ChatEvent::UserTyping { room, .. } => Some(*room),
+            ChatEvent::UserJoined { .. } | ChatEvent::UserLeft { .. } => None,
+        }
+    }
+}
+
+/// This struct represents an event as sent to websocket clients, i.e. with its
+/// sequence number in the event log, e.g. `{"seq":42,"type":"deleted","id":7,"room":1}`.
+/// Events that aren't logged have no sequence number.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SequencedEvent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<Seq>,
+    #[serde(flatten)]
+    pub event: ChatEvent,
+}
+
+/// This enum represents a notice sent to a single websocket client about its
+/// connection, as opposed to the events broadcast to all clients.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Notice {
+    /// The client lagged behind and missed events of the room, or of all rooms if
+    /// `None`, so it should read the messages again. The number of missed events is
+    /// unknown if they were purged from the event log.
+    Resync {
+        room: Option<Id>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        missed: Option<u64>,
+    },
+}
+
+/// This enum represents what a subscription yields to a client.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Update {
+    Event(SequencedEvent),
+    Notice(Notice),
+}
+
+/// This struct represents a command sent by a websocket client.
+#[derive(Deserialize, Serialize)]
+pub struct Command {
+    /// An ID chosen by the client and echoed in the reply, so that the client can
+    /// tell which command a reply is for.
+    #[serde(default)]
+    pub id: Value,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+/// This enum represents what a websocket command does.
+///
+/// It is serialized with a `type` field, like [`ChatEvent`], and the parameters
+/// of the equivalent REST request, e.g. `{"type":"edit","message":42,"text":"Hi"}`.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Action {
+    /// Create a message, like `POST /messages`.
+    Send(CreateMessage),
+    /// Update a message, like `PUT /messages`.
+    Edit(UpdateMessage),
+    /// Delete a message, like `DELETE /messages`.
+    Delete(DeleteMessage),
+    /// Tell the other clients subscribed to the room, or to the default room if
+    /// `None`, that the user is typing. It's acknowledged even if throttled.
+    Typing { room: Option<Id> },
+    /// Check that the connection is alive.
+    Ping,
+}
+
+impl Action {
+    /// This method returns the method and path of the equivalent REST route, so that
+    /// commands share the rate limits of the REST API.
+    fn route(&self) -> Option<(Method, &'static str)> {
+        match self {
+            Action::Send(_) => Some((Method::POST, "/messages")),
+            Action::Edit(_) => Some((Method::PUT, "/messages")),
+            Action::Delete(_) => Some((Method::DELETE, "/messages")),
+            Action::Typing { .. } | Action::Ping => None,
+        }
+    }
+}
+
+/// This enum represents the reply to a websocket command.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Reply {
+    /// The command succeeded, with the message it created, updated or deleted, if any.
+    Ack {
+        id: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<ChitChatMessage>,
+    },
+    /// The command failed, with the status and error of the equivalent REST request.
+    Error {
+        id: Value,
+        status: u16,
+        error: String,
+    },
+    /// The reply to a `ping` command.
+    Pong { id: Value },
+}
+
+/// This struct contains the query parameters of the `/websocket` endpoint.
+#[derive(Deserialize)]
+pub struct Subscription {
+    /// A comma-separated list of room IDs, e.g. `1,2,3`. If `None`, the websocket
+    /// client receives events from all rooms.
+    room: Option<String>,
+    /// The session token of the user, for clients that can't set the `Authorization`
+    /// header, e.g. browsers. Anonymous clients receive events, but can only `ping`.
+    token: Option<String>,
+    /// The sequence number of the last event received by the client, if it's
+    /// reconnecting, to replay the events logged since then.
+    since: Option<Seq>,
+}
 
 /// This function builds and returns the router for the `/websocket` endpoint.
 pub fn make_router() -> Router {
@@ -15,46 +250,976 @@ pub fn make_router() -> Router {
 
 /// This function handles the lifecycle of a websocket connection.
 ///
-/// After a successful upgrade, we subscribe to the broadcast channel and await
-/// messages in a loop. When a message is received, we serialize it to JSON and
-/// forward it to the websocket client, until the client disconnects.
-async fn websocket_handler(ws: WebSocketUpgrade, state: StateExt) -> impl IntoResponse {
-    ws.on_upgrade(|socket: WebSocket| async move {
-        tracing::info!("websocket client connected");
-        let (mut sender, mut receiver) = socket.split();
-        let mut rx = state.tx.subscribe();
-        let mut send_task = tokio::spawn(async move {
-            while let Ok(message) = rx.recv().await {
-                // Safe unwrap: Message -> JSON serialization cannot fail.
-                let json = serde_json::to_string(&message).unwrap();
-                if let Err(error) = sender.send(Message::Text(json)).await {
-                    // If an error occured, assume the client disconnected and exit
-                    // the loop. Unfortunately, `axum::Error` doesn't give us details.
-                    tracing::warn!("failed to send websocket message: {}", error);
-                    break;
+/// Before the upgrade, we authenticate the user, if any, with the bearer token or
+/// the `token` query parameter. After a successful upgrade, we subscribe to the
+/// broadcast channels of the requested rooms, replay the logged events the client
+/// missed if any and, until the client disconnects, forward the events to the
+/// client and reply to the commands it sends.
+async fn websocket_handler(
+    ws: WebSocketUpgrade,
+    params: Query<Subscription>,
+    user: MaybeAuthUser,
+    state: StateExt,
+) -> Result<impl IntoResponse> {
+    let rooms = parse_rooms(params.0.room.as_deref())?;
+    for &room in &rooms {
+        state.db.read_room(room).await.map_err(wrap_error)?;
+    }
+    let since = params.0.since;
+    let user = match (user.0, &params.0.token) {
+        (Some(user), _) => Some(user.id),
+        (None, Some(token)) => Some(
+            state
+                .db
+                .authenticate_token(token)
+                .await
+                .map_err(wrap_error)?,
+        ),
+        (None, None) => None,
+    };
+    Ok(ws.on_upgrade(move |socket: WebSocket| async move {
+        tracing::info!("websocket client connected to rooms {:?}", rooms);
+        if let Some(user) = user {
+            presence::connect(user, &state).await;
+        }
+        handle_socket(socket, &rooms, since, user, &state).await;
+        if let Some(user) = user {
+            presence::disconnect(user, &state);
+        }
+        tracing::info!("websocket client disconnected");
+    }))
+}
+
+/// This function forwards the events of the input rooms to the websocket client,
+/// and runs its commands, until the client disconnects, stops answering pings, or
+/// reaches the end of the connection lifetime.
+///
+/// Events and commands are handled in a single loop, so that the reply to a command
+/// is always sent before the events it caused.
+async fn handle_socket(
+    mut socket: WebSocket,
+    rooms: &[Id],
+    since: Option<Seq>,
+    user: Option<Id>,
+    state: &StateExt,
+) {
+    let mut events = subscribe(rooms, since, state);
+    let ping_interval = Duration::from_secs(state.config.keep_alive_interval);
+    let pong_timeout = Duration::from_secs(state.config.pong_timeout);
+    let lifetime = Duration::from_secs(state.config.connection_lifetime);
+    let mut pings = tokio::time::interval_at(Instant::now() + ping_interval, ping_interval);
+    let end_of_life = tokio::time::sleep(lifetime);
+    tokio::pin!(end_of_life);
+    // The deadline of the pong to the oldest unanswered ping, if any.
+    let mut pong_deadline: Option<Instant> = None;
+    loop {
+        let sent = tokio::select! {
+            update = events.next() => match update {
+                // Users don't need to be told that they're typing.
+                Some(Update::Event(SequencedEvent {
+                    event: ChatEvent::UserTyping { user: typist, .. },
+                    ..
+                })) if Some(typist) == user => continue,
+                Some(update) => send(&mut socket, &update).await,
+                None => break,
+            },
+            message = socket.recv() => {
+                // Any message, not only a pong, shows that the client is alive.
+                pong_deadline = None;
+                match message {
+                    Some(Ok(Message::Text(json))) => {
+                        let reply = run_command(&json, user, state).await;
+                        send(&mut socket, &reply).await
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // Pings are answered by axum, and binary messages aren't commands.
+                    Some(Ok(_)) => continue,
                 }
+            },
+            _ = pings.tick() => {
+                pong_deadline.get_or_insert_with(|| Instant::now() + pong_timeout);
+                socket.send(Message::Ping(Vec::new())).await.ok()
+            },
+            // The deadline is only awaited if set, but must always be evaluated.
+            _ = tokio::time::sleep_until(pong_deadline.unwrap_or_else(Instant::now)),
+                if pong_deadline.is_some() => {
+                tracing::warn!("websocket client didn't answer pings in time");
+                drop(events);
+                close(socket, "Ping timeout").await;
+                return;
+            },
+            _ = &mut end_of_life => {
+                drop(events);
+                close(socket, "Connection lifetime exceeded").await;
+                return;
+            },
+        };
+        if sent.is_none() {
+            break;
+        }
+    }
+}
+
+/// This function closes the connection with the websocket client, telling it why
+/// with the close code 1001 (going away), then waits for the client to answer
+/// with its own close frame, as required by the websocket protocol.
+async fn close(mut socket: WebSocket, reason: &'static str) {
+    let frame = CloseFrame {
+        code: CLOSE_GOING_AWAY,
+        reason: Cow::Borrowed(reason),
+    };
+    if let Err(error) = socket.send(Message::Close(Some(frame))).await {
+        tracing::warn!("failed to close websocket: {}", error);
+        return;
+    }
+    let answer = async {
+        while let Some(Ok(message)) = socket.recv().await {
+            if let Message::Close(_) = message {
+                break;
             }
-        });
-        let mut recv_task = tokio::spawn(async move {
-            // Ignore all messages sent by the websocket client until they disconnect.
-            while let Some(Ok(message)) = receiver.next().await {
-                if let Message::Close(_) = message {
-                    tracing::info!("websocket client disconnected");
-                    break;
+        }
+    };
+    if tokio::time::timeout(CLOSE_TIMEOUT, answer).await.is_err() {
+        tracing::warn!("websocket client didn't answer the close frame in time");
+    }
+}
+
+/// This function sends the input value serialized to JSON to the websocket client,
+/// and returns `None` if it failed, i.e. if the client disconnected.
+async fn send(socket: &mut WebSocket, value: &impl Serialize) -> Option<()> {
+    // Safe unwrap: Update and Reply -> JSON serialization cannot fail.
+    let json = serde_json::to_string(value).unwrap();
+    if let Err(error) = socket.send(Message::Text(json)).await {
+        // If an error occured, assume the client disconnected. Unfortunately,
+        // `axum::Error` doesn't give us details.
+        tracing::warn!("failed to send websocket message: {}", error);
+        return None;
+    }
+    Some(())
+}
+
+/// This function parses and runs a command sent by the websocket client, and
+/// returns the reply to send back.
+async fn run_command(json: &str, user: Option<Id>, state: &StateExt) -> Reply {
+    let command: Command = match serde_json::from_str(json) {
+        Ok(command) => command,
+        Err(error) => {
+            return Reply::Error {
+                id: Value::Null,
+                status: StatusCode::BAD_REQUEST.as_u16(),
+                error: format!("Invalid command: {}", error),
+            }
+        }
+    };
+    let id = command.id;
+    if let Action::Ping = command.action {
+        return Reply::Pong { id };
+    }
+    match run_action(command.action, user, state).await {
+        Ok(message) => Reply::Ack { id, message },
+        Err((status, error)) => Reply::Error {
+            id,
+            status: status.as_u16(),
+            error,
+        },
+    }
+}
+
+/// This function runs the action of a command on behalf of the user, and returns
+/// the message it affected, if any.
+async fn run_action(
+    action: Action,
+    user: Option<Id>,
+    state: &StateExt,
+) -> Result<Option<ChitChatMessage>> {
+    let user = user.ok_or_else(|| {
+        let error = "Missing bearer token".to_string();
+        (StatusCode::UNAUTHORIZED, error)
+    })?;
+    if let Some((method, path)) = action.route() {
+        let client = Client::User(user);
+        let wait = state.limiter.check(&method, path, client);
+        wait.map_err(rate_limit_error)?;
+    }
+    match action {
+        Action::Send(params) => messages::create_and_broadcast(user, params, state)
+            .await
+            .map(Some),
+        Action::Edit(params) => messages::update_and_broadcast(user, params, state)
+            .await
+            .map(Some),
+        Action::Delete(params) => messages::delete_and_broadcast(user, params, state)
+            .await
+            .map(Some),
+        Action::Typing { room } => {
+            let room = room.unwrap_or(DEFAULT_ROOM);
+            state.db.read_room(room).await.map_err(wrap_error)?;
+            let interval = Duration::from_secs(state.config.typing_interval);
+            if state.typing.check(user, room, interval) {
+                let expires_in = state.config.typing_timeout;
+                let event = ChatEvent::UserTyping {
+                    room,
+                    user,
+                    expires_in,
+                };
+                broadcast_message(event, state).await;
+            }
+            Ok(None)
+        }
+        Action::Ping => Ok(None),
+    }
+}
+
+/// This struct holds when the last typing indicator of each user in each room was
+/// broadcast, so that they're broadcast at most once per interval.
+#[derive(Default)]
+pub struct TypingThrottle {
+    /// The instants of the last indicators, indexed by user and room.
+    last: Mutex<HashMap<(Id, Id), Instant>>,
+}
+
+impl TypingThrottle {
+    /// This method returns whether a typing indicator of the user in the room may be
+    /// broadcast, i.e. whether the last one is older than the interval, and if so,
+    /// records it.
+    pub fn check(&self, user: Id, room: Id, interval: Duration) -> bool {
+        let now = Instant::now();
+        let mut last = self.last.lock();
+        match last.get(&(user, room)) {
+            Some(instant) if now.duration_since(*instant) < interval => false,
+            _ => {
+                last.insert((user, room), now);
+                true
+            }
+        }
+    }
+
+    /// This method drops the indicators older than the interval, which don't
+    /// throttle anymore, and returns how many were dropped.
+    pub fn evict_idle_typists(&self, interval: Duration) -> usize {
+        let now = Instant::now();
+        let mut last = self.last.lock();
+        let count = last.len();
+        last.retain(|_, instant| now.duration_since(*instant) < interval);
+        count - last.len()
+    }
+}
+
+/// This function runs in the background for the entire duration of the application,
+/// and periodically drops the typing indicators that don't throttle anymore.
+pub async fn evict_idle_typists(state: Arc<State>) {
+    let throttle = Duration::from_secs(state.config.typing_interval);
+    let mut interval = tokio::time::interval(TYPING_EVICTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        let count = state.typing.evict_idle_typists(throttle);
+        if count > 0 {
+            tracing::info!("dropped {} idle typing indicators", count);
+        }
+    }
+}
+
+/// This function parses the comma-separated list of room IDs of a subscription.
+pub fn parse_rooms(rooms: Option<&str>) -> Result<Vec<Id>> {
+    let rooms = match rooms {
+        Some(rooms) => rooms,
+        None => return Ok(Vec::new()),
+    };
+    let mut ids = Vec::new();
+    for room in rooms.split(',') {
+        match room.trim().parse() {
+            Ok(id) if !ids.contains(&id) => ids.push(id),
+            Ok(_) => continue,
+            Err(_) => {
+                let error = format!("Invalid room ID: {}", room);
+                return Err((StatusCode::BAD_REQUEST, error));
+            }
+        }
+    }
+    Ok(ids)
+}
+
+/// This function returns the stream of updates to send to a client subscribed to
+/// the input rooms, or to all rooms if none is given: the events logged after
+/// `since` if the client is resuming, then the live events.
+///
+/// The live events are subscribed to before the logged events are read, so that
+/// no event is missed in between, and those already replayed are skipped. If the
+/// logged events can't be replayed, only the live events logged after the resync
+/// are sent.
+pub fn subscribe(
+    rooms: &[Id],
+    since: Option<Seq>,
+    state: &Arc<State>,
+) -> BoxStream<'static, Update> {
+    let live = subscribe_live(rooms, state).map(|update| (None, update));
+    let replayed = match since {
+        Some(since) => replay(since, rooms.to_vec(), state.clone())
+            .map(|(seq, update)| (Some(seq), update))
+            .boxed(),
+        None => futures::stream::empty().boxed(),
+    };
+    let mut last = since.unwrap_or(0);
+    let updates = replayed.chain(live);
+    let updates = updates.filter_map(move |(replayed_seq, update)| {
+        let keep = match (replayed_seq, &update) {
+            (Some(seq), _) => {
+                last = seq;
+                true
+            }
+            (None, Update::Event(SequencedEvent { seq: Some(seq), .. })) => *seq > last,
+            (None, _) => true,
+        };
+        futures::future::ready(if keep { Some(update) } else { None })
+    });
+    updates.boxed()
+}
+
+/// This function returns the stream of the events of the input rooms, or of all
+/// rooms if none is given, logged after `since`. If they can't be replayed, e.g.
+/// because they were purged or `since` is ahead of the event log, the stream yields
+/// a [`Notice::Resync`] instead.
+///
+/// Each update comes with the sequence number after which live events are new: the
+/// sequence number of the event, or the last one logged for a resync, since the
+/// client reads the messages again anyway.
+fn replay(since: Seq, rooms: Vec<Id>, state: Arc<State>) -> impl Stream<Item = (Seq, Update)> {
+    let batches = futures::stream::unfold(Some(since), move |last| {
+        let rooms = rooms.clone();
+        let state = state.clone();
+        async move {
+            let last = last?;
+            match state.db.read_events(last, &rooms, REPLAY_BATCH).await {
+                Ok(Some(events)) => {
+                    tracing::info!("replaying {} events logged after {}", events.len(), last);
+                    let next = match events.last() {
+                        Some(event) if events.len() == REPLAY_BATCH as usize => Some(event.seq),
+                        _ => None,
+                    };
+                    let updates: Vec<_> = events.into_iter().filter_map(parse_event).collect();
+                    Some((updates, next))
+                }
+                result => {
+                    if let Err(error) = result {
+                        tracing::warn!("failed to read logged events: {}", error);
+                    }
+                    let notice = Notice::Resync {
+                        room: None,
+                        missed: None,
+                    };
+                    let head = state.db.read_last_seq().await.unwrap_or(0);
+                    Some((vec![(head, Update::Notice(notice))], None))
                 }
             }
+        }
+    });
+    batches.flat_map(futures::stream::iter)
+}
+
+/// This function returns the update of a logged event with its sequence number,
+/// unless it can't be parsed.
+fn parse_event(logged: LoggedEvent) -> Option<(Seq, Update)> {
+    match serde_json::from_str(&logged.payload) {
+        Ok(event) => {
+            let seq = Some(logged.seq);
+            Some((logged.seq, Update::Event(SequencedEvent { seq, event })))
+        }
+        Err(error) => {
+            tracing::warn!("failed to parse event {}: {}", logged.seq, error);
+            None
+        }
+    }
+}
+
+/// This function subscribes to the broadcast channels of the input rooms, or to
+/// the channel of all rooms if none is given, and merges them into one stream.
+///
+/// If the client lags behind a channel, i.e. the channel drops events before the
+/// client receives them, the stream yields a [`Notice::Resync`] and goes on with
+/// the oldest events still in the channel.
+fn subscribe_live(rooms: &[Id], state: &State) -> SelectAll<BoxStream<'static, Update>> {
+    let receivers = if rooms.is_empty() {
+        vec![(None, state.tx.subscribe())]
+    } else {
+        let subscribe = |&room| (Some(room), state.subscribe_room(room));
+        rooms.iter().map(subscribe).collect()
+    };
+    let streams = receivers
+        .into_iter()
+        .map(|(room, rx): (_, Receiver<SequencedEvent>)| {
+            let stream = futures::stream::unfold(rx, move |mut rx| async move {
+                let update = match rx.recv().await {
+                    Ok(event) => Update::Event(event),
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!(
+                            "websocket client lagged behind by {} events in room {:?}",
+                            missed,
+                            room
+                        );
+                        let missed = Some(missed);
+                        Update::Notice(Notice::Resync { room, missed })
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                Some((update, rx))
+            });
+            stream.boxed()
         });
-        // If either task exits, abort the other.
-        tokio::select! {
-            _ = (&mut send_task) => recv_task.abort(),
-            _ = (&mut recv_task) => send_task.abort(),
-        };
-    })
+    futures::stream::select_all(streams)
 }
 
-/// This function broadcasts a chitchat message to all connected clients.
-pub fn broadcast_message(message: ChitChatMessage, state: &StateExt) {
-    let count = state.tx.send(message).unwrap_or(0);
+/// This function logs a chitchat event, unless it's ephemeral, and broadcasts it to
+/// all connected clients subscribed to all rooms or to the room in which the event
+/// occurred, i.e. to all clients if it concerns all rooms.
+pub async fn broadcast_message(event: ChatEvent, state: &StateExt) {
+    let room = event.room();
+    let logged_room = room.filter(|_| event.is_logged());
+    // Hold the lock until the event is broadcast, so that clients receive events in
+    // the order of their sequence numbers and can resume from the last one. Ephemeral
+    // events have no sequence number, so they don't wait for other events to be logged.
+    let _event_log = match logged_room {
+        Some(_) => Some(state.event_log.lock().await),
+        None => None,
+    };
+    let seq = match logged_room {
+        Some(room) => {
+            // Safe unwrap: ChatEvent -> JSON serialization cannot fail.
+            let payload = serde_json::to_string(&event).unwrap();
+            match state.db.create_event(room, payload).await {
+                Ok(seq) => Some(seq),
+                Err(error) => {
+                    tracing::warn!("failed to log event: {}", error);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let event = SequencedEvent { seq, event };
+    let mut count = state.tx.send(event.clone()).unwrap_or(0);
+    let mut rooms = state.rooms.lock();
+    match room {
+        Some(room) => {
+            if let Some(tx) = rooms.get(&room) {
+                match tx.send(event) {
+                    Ok(room_count) => count += room_count,
+                    // Nobody listens to this room anymore, so drop its channel.
+                    Err(_) => drop(rooms.remove(&room)),
+                }
+            }
+        }
+        None => rooms.retain(|_, tx| match tx.send(event.clone()) {
+            Ok(room_count) => {
+                count += room_count;
+                true
+            }
+            Err(_) => false,
+        }),
+    }
     let noun = if count == 1 { "client" } else { "clients" };
     tracing::info!("websocket message sent to {} {}", count, noun);
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::{ChatEvent, Notice, Reply, SequencedEvent, TypingThrottle};
+    use crate::database::{CreateMessage, DeleteMessage, UpdateMessage, DEFAULT_ROOM};
+    use crate::messages::tests::{react, send, Method};
+    use futures::{SinkExt, StreamExt};
+    use reqwest::StatusCode;
+    use serde_json::json;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+    pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    pub async fn connect(addr: SocketAddr) -> Socket {
+        connect_to(addr, "").await
+    }
+
+    pub async fn connect_to(addr: SocketAddr, query: &str) -> Socket {
+        let url = format!("ws://{}/websocket?{}", addr, query);
+        let (socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        // The server subscribes to the broadcast channel after the upgrade
+        // completes, so give it a moment before triggering any event.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        socket
+    }
+
+    pub async fn next_event(socket: &mut Socket) -> ChatEvent {
+        serde_json::from_str(&next_text(socket).await).unwrap()
+    }
+
+    /// Send a command through the websocket, and return the reply to it.
+    pub async fn run_command(socket: &mut Socket, command: serde_json::Value) -> Reply {
+        let json = serde_json::to_string(&command).unwrap();
+        socket.send(Message::Text(json)).await.unwrap();
+        serde_json::from_str(&next_text(socket).await).unwrap()
+    }
+
+    async fn next_text(socket: &mut Socket) -> String {
+        let timeout = Duration::from_secs(5);
+        loop {
+            let message = tokio::time::timeout(timeout, socket.next()).await;
+            match message
+                .expect("timed out waiting for an event")
+                .unwrap()
+                .unwrap()
+            {
+                Message::Text(json) => return json,
+                Message::Ping(_) | Message::Pong(_) => continue,
+                message => panic!("unexpected websocket message: {:?}", message),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn it_runs_commands() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (user1, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let mut socket = connect_to(addr, &format!("token={}", token1)).await;
+
+        let command = json!({ "id": 1, "type": "send", "text": "Hello" });
+        let message1 = match run_command(&mut socket, command).await {
+            Reply::Ack {
+                id,
+                message: Some(message),
+            } if id == 1 => message,
+            reply => panic!("unexpected reply: {:?}", reply),
+        };
+        assert_eq!(user1.id, message1.author);
+        assert_eq!("Hello", message1.text);
+        let event = ChatEvent::Created(message1.clone());
+        assert_eq!(event, next_event(&mut socket).await);
+
+        let command = json!({ "id": "a", "type": "edit", "message": message1.id, "text": "Hi" });
+        match run_command(&mut socket, command).await {
+            Reply::Ack {
+                id,
+                message: Some(message),
+            } if id == "a" => assert_eq!("Hi", message.text),
+            reply => panic!("unexpected reply: {:?}", reply),
+        };
+        match next_event(&mut socket).await {
+            ChatEvent::Updated(message) => assert_eq!("Hi", message.text),
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        let command = json!({ "id": 2, "type": "typing" });
+        let reply = Reply::Ack {
+            id: json!(2),
+            message: None,
+        };
+        // The user isn't told that they're typing, so the next event is the deletion.
+        assert_eq!(reply, run_command(&mut socket, command).await);
+
+        let command = json!({ "id": 3, "type": "delete", "message": message1.id });
+        match run_command(&mut socket, command).await {
+            Reply::Ack {
+                message: Some(message),
+                ..
+            } => assert!(message.deleted),
+            reply => panic!("unexpected reply: {:?}", reply),
+        };
+        let event = ChatEvent::Deleted {
+            id: message1.id,
+            room: message1.room,
+        };
+        assert_eq!(event, next_event(&mut socket).await);
+
+        let command = json!({ "id": 4, "type": "ping" });
+        let reply = Reply::Pong { id: json!(4) };
+        assert_eq!(reply, run_command(&mut socket, command).await);
+    }
+
+    #[tokio::test]
+    async fn it_replays_missed_events() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let room1 = crate::rooms::tests::create_room(&client, addr, &token1, "room1").await;
+        let create = |room, text: &str| {
+            Method::Post(CreateMessage {
+                room,
+                reply_to: None,
+                text: text.to_string(),
+            })
+        };
+        let mut socket = connect(addr).await;
+        let message1 = send(&client, addr, &token1, &create(None, "1"))
+            .await
+            .unwrap();
+        let event: SequencedEvent = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+        assert_eq!(ChatEvent::Created(message1), event.event);
+        let since = event.seq.unwrap();
+        drop(socket);
+
+        let message2 = send(&client, addr, &token1, &create(None, "2"))
+            .await
+            .unwrap();
+        let message3 = send(&client, addr, &token1, &create(Some(room1.id), "3"))
+            .await
+            .unwrap();
+        let query = format!("since={}", since);
+        let mut socket = connect_to(addr, &query).await;
+        for (i, message) in [message2, message3.clone()].into_iter().enumerate() {
+            let event = SequencedEvent {
+                seq: Some(since + 1 + i as i64),
+                event: ChatEvent::Created(message),
+            };
+            let json = next_text(&mut socket).await;
+            assert_eq!(event, serde_json::from_str(&json).unwrap());
+        }
+
+        // Live events follow the replayed ones, and other rooms aren't replayed.
+        let message4 = send(&client, addr, &token1, &create(None, "4"))
+            .await
+            .unwrap();
+        assert_eq!(ChatEvent::Created(message4), next_event(&mut socket).await);
+        let query = format!("since={}&room={}", since, room1.id);
+        let mut socket = connect_to(addr, &query).await;
+        assert_eq!(ChatEvent::Created(message3), next_event(&mut socket).await);
+
+        // A client ahead of the event log, e.g. after the database was reset, resyncs
+        // and still gets the live events.
+        let mut socket = connect_to(addr, &format!("since={}", i64::MAX)).await;
+        let notice = Notice::Resync {
+            room: None,
+            missed: None,
+        };
+        let json = next_text(&mut socket).await;
+        assert_eq!(notice, serde_json::from_str(&json).unwrap());
+        let message5 = send(&client, addr, &token1, &create(None, "5"))
+            .await
+            .unwrap();
+        assert_eq!(ChatEvent::Created(message5), next_event(&mut socket).await);
+    }
+
+    #[tokio::test]
+    async fn it_resyncs_lagged_clients() {
+        let args = ["--channel-capacity", "1"];
+        let (client, addr) = crate::tests::start_client_and_server_with(&args).await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let mut socket = connect_to(addr, &format!("token={}", token1)).await;
+        let command = json!({ "type": "send", "text": "Hello" });
+        let message1 = match run_command(&mut socket, command).await {
+            Reply::Ack {
+                message: Some(message),
+                ..
+            } => message,
+            reply => panic!("unexpected reply: {:?}", reply),
+        };
+        next_event(&mut socket).await;
+
+        // The socket isn't read while the command runs, so the `created` event of
+        // the reply is dropped for the `thread_updated` event.
+        let command = json!({ "type": "send", "reply_to": message1.id, "text": "Hi" });
+        run_command(&mut socket, command).await;
+        let notice = Notice::Resync {
+            room: None,
+            missed: Some(1),
+        };
+        assert_eq!(
+            notice,
+            serde_json::from_str(&next_text(&mut socket).await).unwrap()
+        );
+        match next_event(&mut socket).await {
+            ChatEvent::ThreadUpdated { reply_count, .. } => assert_eq!(1, reply_count),
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        let command = json!({ "id": 1, "type": "ping" });
+        let reply = Reply::Pong { id: json!(1) };
+        assert_eq!(reply, run_command(&mut socket, command).await);
+    }
+
+    /// Read the messages of the websocket until it's closed, and return the close
+    /// code and reason, along with the number of pings received before.
+    async fn read_until_closed(socket: &mut Socket) -> (u16, String, usize) {
+        let mut pings = 0;
+        loop {
+            let timeout = Duration::from_secs(5);
+            let message = tokio::time::timeout(timeout, socket.next()).await;
+            match message
+                .expect("timed out waiting for close")
+                .unwrap()
+                .unwrap()
+            {
+                Message::Ping(_) => pings += 1,
+                Message::Close(Some(frame)) => {
+                    return (frame.code.into(), frame.reason.to_string(), pings)
+                }
+                message => panic!("unexpected websocket message: {:?}", message),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn it_closes_unresponsive_connections() {
+        let args = ["--keep-alive-interval", "1", "--pong-timeout", "1"];
+        let (_, addr) = crate::tests::start_client_and_server_with(&args).await;
+        let mut alive = connect(addr).await;
+        let mut dead = connect(addr).await;
+
+        // Reading the socket answers pings, so the alive client is still pinged
+        // after the first pong timeout.
+        for _ in 0..3 {
+            let timeout = Duration::from_secs(5);
+            let message = tokio::time::timeout(timeout, alive.next()).await;
+            match message
+                .expect("timed out waiting for ping")
+                .unwrap()
+                .unwrap()
+            {
+                Message::Ping(_) => continue,
+                message => panic!("unexpected websocket message: {:?}", message),
+            }
+        }
+        let command = json!({ "id": 1, "type": "ping" });
+        let reply = Reply::Pong { id: json!(1) };
+        assert_eq!(reply, run_command(&mut alive, command).await);
+
+        // The dead client hasn't read anything, so it didn't answer the first ping.
+        let (code, reason, pings) = read_until_closed(&mut dead).await;
+        assert_eq!((1001, "Ping timeout"), (code, reason.as_str()));
+        assert!(pings >= 1);
+    }
+
+    #[tokio::test]
+    async fn it_closes_connections_at_end_of_life() {
+        let args = ["--connection-lifetime", "1"];
+        let (_, addr) = crate::tests::start_client_and_server_with(&args).await;
+        let mut socket = connect(addr).await;
+
+        let (code, reason, _) = read_until_closed(&mut socket).await;
+        assert_eq!(
+            (1001, "Connection lifetime exceeded"),
+            (code, reason.as_str())
+        );
+    }
+
+    #[test]
+    fn it_evicts_idle_typists() {
+        let throttle = TypingThrottle::default();
+        let interval = Duration::from_millis(10);
+
+        assert!(throttle.check(1, DEFAULT_ROOM, interval));
+        assert!(!throttle.check(1, DEFAULT_ROOM, interval));
+        assert_eq!(0, throttle.evict_idle_typists(interval));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(1, throttle.evict_idle_typists(interval));
+        assert!(throttle.check(1, DEFAULT_ROOM, interval));
+    }
+
+    #[tokio::test]
+    async fn it_throttles_typing_indicators() {
+        let args = ["--typing-interval", "1", "--typing-timeout", "3"];
+        let (client, addr) = crate::tests::start_client_and_server_with(&args).await;
+        let (user1, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let mut typist = connect_to(addr, &format!("token={}", token1)).await;
+        let mut observer = connect(addr).await;
+        let typing = ChatEvent::UserTyping {
+            room: DEFAULT_ROOM,
+            user: user1.id,
+            expires_in: 3,
+        };
+
+        for id in 1..=2 {
+            let command = json!({ "id": id, "type": "typing" });
+            let reply = Reply::Ack {
+                id: json!(id),
+                message: None,
+            };
+            assert_eq!(reply, run_command(&mut typist, command).await);
+        }
+        assert_eq!(typing, next_event(&mut observer).await);
+
+        // The second indicator was throttled, and the typist isn't told about any.
+        let create = Method::Post(CreateMessage {
+            room: None,
+            reply_to: None,
+            text: "Done".to_string(),
+        });
+        let message = send(&client, addr, &token1, &create).await.unwrap();
+        let event = ChatEvent::Created(message);
+        assert_eq!(event, next_event(&mut observer).await);
+        assert_eq!(event, next_event(&mut typist).await);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let command = json!({ "id": 3, "type": "typing" });
+        run_command(&mut typist, command).await;
+        assert_eq!(typing, next_event(&mut observer).await);
+    }
+
+    #[tokio::test]
+    async fn it_replies_to_failed_commands() {
+        let args = ["--rate-limits", "POST /messages=1/60"];
+        let (client, addr) = crate::tests::start_client_and_server_with(&args).await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let mut socket = connect_to(addr, &format!("token={}", token1)).await;
+
+        let command = json!({ "id": 1, "type": "send", "text": "What the fuck" });
+        let reply = Reply::Error {
+            id: json!(1),
+            status: 400,
+            error: "No swear words please!".to_string(),
+        };
+        assert_eq!(reply, run_command(&mut socket, command).await);
+
+        let command = json!({ "id": 2, "type": "send", "text": "Hello" });
+        let reply = Reply::Error {
+            id: json!(2),
+            status: 429,
+            error: "Too many requests, retry in 60 seconds".to_string(),
+        };
+        assert_eq!(reply, run_command(&mut socket, command).await);
+
+        match run_command(&mut socket, json!({ "id": 3, "type": "shout" })).await {
+            Reply::Error { id, status, .. } => assert_eq!((json!(null), 400), (id, status)),
+            reply => panic!("unexpected reply: {:?}", reply),
+        }
+
+        // Anonymous clients can only ping, and invalid tokens are rejected upfront.
+        let mut socket = connect(addr).await;
+        let command = json!({ "id": 4, "type": "typing" });
+        let reply = Reply::Error {
+            id: json!(4),
+            status: 401,
+            error: "Missing bearer token".to_string(),
+        };
+        assert_eq!(reply, run_command(&mut socket, command).await);
+        let url = format!("ws://{}/websocket?token=invalid", addr);
+        assert!(tokio_tungstenite::connect_async(url).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_broadcasts_typed_events() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let mut socket = connect(addr).await;
+
+        let method = Method::Post(CreateMessage {
+            room: None,
+            reply_to: None,
+            text: "Hello".to_string(),
+        });
+        let message1 = send(&client, addr, &token1, &method).await.unwrap();
+        let event = ChatEvent::Created(message1.clone());
+        assert_eq!(event, next_event(&mut socket).await);
+
+        let method = Method::Put(UpdateMessage {
+            message: message1.id,
+            text: "Hello, World!".to_string(),
+        });
+        let updated_message1 = send(&client, addr, &token1, &method).await.unwrap();
+        let event = ChatEvent::Updated(updated_message1.clone());
+        assert_eq!(event, next_event(&mut socket).await);
+
+        let method = Method::Delete(DeleteMessage {
+            message: message1.id,
+        });
+        send(&client, addr, &token1, &method).await.unwrap();
+        let event = ChatEvent::Deleted {
+            id: message1.id,
+            room: message1.room,
+        };
+        assert_eq!(event, next_event(&mut socket).await);
+
+        let url = format!("http://{}/messages/{}/restore", addr, message1.id);
+        let response = client.post(url).bearer_auth(&token1).send().await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let event = ChatEvent::Restored(updated_message1);
+        assert_eq!(event, next_event(&mut socket).await);
+    }
+
+    #[tokio::test]
+    async fn it_broadcasts_thread_updates() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let method = Method::Post(CreateMessage {
+            room: None,
+            reply_to: None,
+            text: "Hello".to_string(),
+        });
+        let message1 = send(&client, addr, &token1, &method).await.unwrap();
+        let mut socket = connect(addr).await;
+
+        let method = Method::Post(CreateMessage {
+            room: None,
+            reply_to: Some(message1.id),
+            text: "Hello back".to_string(),
+        });
+        let reply1 = send(&client, addr, &token1, &method).await.unwrap();
+        let event = ChatEvent::Created(reply1.clone());
+        assert_eq!(event, next_event(&mut socket).await);
+        let event = ChatEvent::ThreadUpdated {
+            id: message1.id,
+            room: message1.room,
+            reply_count: 1,
+        };
+        assert_eq!(event, next_event(&mut socket).await);
+
+        let method = Method::Delete(DeleteMessage { message: reply1.id });
+        send(&client, addr, &token1, &method).await.unwrap();
+        let event = ChatEvent::Deleted {
+            id: reply1.id,
+            room: reply1.room,
+        };
+        assert_eq!(event, next_event(&mut socket).await);
+        let event = ChatEvent::ThreadUpdated {
+            id: message1.id,
+            room: message1.room,
+            reply_count: 0,
+        };
+        assert_eq!(event, next_event(&mut socket).await);
+    }
+
+    #[tokio::test]
+    async fn it_broadcasts_reactions() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (user1, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let method = Method::Post(CreateMessage {
+            room: None,
+            reply_to: None,
+            text: "Hello".to_string(),
+        });
+        let message1 = send(&client, addr, &token1, &method).await.unwrap();
+        let mut socket = connect(addr).await;
+
+        react(&client, addr, &token1, message1.id, "🎉")
+            .await
+            .unwrap();
+        let event = ChatEvent::ReactionAdded {
+            id: message1.id,
+            room: message1.room,
+            user: user1.id,
+            emoji: "🎉".to_string(),
+            count: 1,
+        };
+        assert_eq!(event, next_event(&mut socket).await);
+    }
+
+    #[tokio::test]
+    async fn it_only_broadcasts_events_of_subscribed_rooms() {
+        let (client, addr) = crate::tests::start_client_and_server().await;
+        let (_, token1) = crate::users::tests::create_user_with_session(&client, addr).await;
+        let room1 = crate::rooms::tests::create_room(&client, addr, &token1, "room1").await;
+        let mut socket = connect_to(addr, &format!("room={}", room1.id)).await;
+
+        for room in [None, Some(room1.id)] {
+            let method = Method::Post(CreateMessage {
+                room,
+                reply_to: None,
+                text: "Hello".to_string(),
+            });
+            send(&client, addr, &token1, &method).await.unwrap();
+        }
+
+        match next_event(&mut socket).await {
+            ChatEvent::Created(message) => assert_eq!(room1.id, message.room),
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+}
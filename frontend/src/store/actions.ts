@@ -2,7 +2,7 @@
  * This module is responsible for sending all HTTP requests
  * to the REST API endpoints.
  */
-import axios from "axios";
+import axios, { AxiosRequestConfig } from "axios";
 import { ActionContext } from "vuex";
 
 import {
@@ -10,40 +10,61 @@ import {
   DeleteMessageParams,
   State,
   UpdateMessageParams,
+  User,
 } from "@/store/state";
 
 type Context = ActionContext<State, State>;
 
 export default {
   createUser(context: Context): void {
+    const name = `guest-${Math.random().toString(16).slice(2, 8)}`;
     axios
-      .post("http://localhost:3000/users")
-      .then((response) => context.commit("setUser", response.data))
+      .post("http://localhost:3000/users", { name })
+      .then((response) => {
+        context.commit("setUser", response.data);
+        context.dispatch("createSession", response.data);
+      })
+      .catch((error) => errorHandler(context, error.response.data));
+  },
+  createSession(context: Context, user: User): void {
+    axios
+      .post("http://localhost:3000/sessions", user)
+      .then((response) => context.commit("setToken", response.data.token))
       .catch((error) => errorHandler(context, error.response.data));
   },
   readMessages(context: Context): void {
     axios
       .get("http://localhost:3000/messages")
-      .then((response) => context.commit("setMessages", response.data))
+      .then((response) => {
+        context.commit("setMessages", response.data.messages);
+        context.commit("insertProfiles", response.data.users);
+      })
       .catch((error) => errorHandler(context, error.response.data));
   },
   createMessage(context: Context, params: CreateMessageParams): void {
     axios
-      .post("http://localhost:3000/messages", params)
+      .post("http://localhost:3000/messages", params, authConfig(context))
       .catch((error) => errorHandler(context, error.response.data));
   },
   updateMessage(context: Context, params: UpdateMessageParams): void {
     axios
-      .put("http://localhost:3000/messages", params)
+      .put("http://localhost:3000/messages", params, authConfig(context))
       .catch((error) => errorHandler(context, error.response.data));
   },
   deleteMessage(context: Context, params: DeleteMessageParams): void {
     axios
-      .delete("http://localhost:3000/messages", { data: params })
+      .delete("http://localhost:3000/messages", {
+        ...authConfig(context),
+        data: params,
+      })
       .catch((error) => errorHandler(context, error.response.data));
   },
 };
 
+function authConfig(context: Context): AxiosRequestConfig {
+  return { headers: { Authorization: `Bearer ${context.state.token}` } };
+}
+
 function errorHandler(context: Context, errorMessage: string) {
   context.commit("showErrorSnackbar", errorMessage);
 }
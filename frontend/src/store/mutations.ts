@@ -1,10 +1,51 @@
-import { Message, State, User } from "@/store/state";
+import { ChatEvent, Message, Profile, State, User } from "@/store/state";
 import { SnackbarColor } from "@/components/AppSnackbar.vue";
 
 export default {
-  insertMessage(state: State, message: Message): void {
-    state.messages.push(message);
-    state.messages.sort((a, b) => b.id - a.id);
+  applyEvent(state: State, event: ChatEvent): void {
+    switch (event.type) {
+      case "created":
+      case "updated":
+      case "restored":
+        // Replies belong to threads, not to the stream of top-level messages.
+        if (event.parent !== null) break;
+        state.messages = state.messages.filter((m) => m.id !== event.id);
+        state.messages.push(event);
+        state.messages.sort((a, b) => b.id - a.id);
+        break;
+      case "deleted":
+        // Deleted messages are kept as tombstones until they're purged.
+        state.messages
+          .filter((m) => m.id === event.id)
+          .forEach((m) => {
+            m.deleted = true;
+            m.text = "";
+            m.reactions = [];
+          });
+        break;
+      case "thread_updated":
+        state.messages
+          .filter((m) => m.id === event.id)
+          .forEach((m) => (m.reply_count = event.reply_count));
+        break;
+      case "reaction_added":
+      case "reaction_removed":
+        state.messages
+          .filter((m) => m.id === event.id)
+          .forEach((m) => {
+            const reaction = { emoji: event.emoji, count: event.count };
+            const index = m.reactions.findIndex((r) => r.emoji === event.emoji);
+            if (index < 0) m.reactions.push(reaction);
+            else m.reactions.splice(index, 1, reaction);
+            m.reactions = m.reactions.filter((r) => r.count > 0);
+          });
+        break;
+    }
+  },
+  insertProfiles(state: State, profiles: Profile[]): void {
+    const users = { ...state.users };
+    profiles.forEach((profile) => (users[profile.id] = profile));
+    state.users = users;
   },
   setMessages(state: State, messages: Message[]): void {
     state.messages = messages;
@@ -13,6 +54,9 @@ export default {
   setSnackbar(state: State, isOn: boolean): void {
     state.snackbar.isOn = isOn;
   },
+  setToken(state: State, token: string): void {
+    state.token = token;
+  },
   setUser(state: State, user: User): void {
     state.user = user;
   },
@@ -6,7 +6,9 @@ import { Snackbar, SnackbarColor } from "@/components/AppSnackbar.vue";
 export interface State {
   messages: Message[];
   snackbar: Snackbar;
+  token: string | null;
   user: User | null;
+  users: Record<number, Profile>;
 }
 
 /**
@@ -16,11 +18,55 @@ export interface State {
 export interface Message {
   id: number;
   author: number;
+  room: number;
   text: string;
   created: number;
   modified: number | null;
+  parent: number | null;
+  reply_count: number;
+  edit_count: number;
+  flagged: boolean;
+  deleted: boolean;
+  reactions: Reaction[];
 }
 
+/**
+ * This interface is the type definition of
+ * the `Reaction` struct in the backend.
+ */
+export interface Reaction {
+  emoji: string;
+  count: number;
+}
+
+/**
+ * This interface is the type definition of the
+ * fields of the reaction events in the backend.
+ */
+export interface ReactionEvent {
+  id: number;
+  room: number;
+  user: number;
+  emoji: string;
+  count: number;
+}
+
+/**
+ * This type is the type definition of
+ * the `ChatEvent` enum in the backend.
+ */
+export type ChatEvent =
+  | ({ type: "created" } & Message)
+  | ({ type: "updated" } & Message)
+  | { type: "deleted"; id: number; room: number }
+  | ({ type: "restored" } & Message)
+  | { type: "thread_updated"; id: number; room: number; reply_count: number }
+  | ({ type: "reaction_added" } & ReactionEvent)
+  | ({ type: "reaction_removed" } & ReactionEvent)
+  | { type: "user_typing"; room: number; user: number; expires_in: number }
+  | { type: "user_joined"; user: number }
+  | { type: "user_left"; user: number };
+
 /**
  * This interface is the type definition of
  * the `User` struct in the backend.
@@ -30,12 +76,24 @@ export interface User {
   password: string;
 }
 
+/**
+ * This interface is the type definition of
+ * the `Profile` struct in the backend.
+ */
+export interface Profile {
+  id: number;
+  name: string;
+  color: string | null;
+  bio: string | null;
+}
+
 /**
  * This interface is the type definition of
  * the `CreateMessageParams` struct in the backend.
  */
 export interface CreateMessageParams {
-  user: User;
+  room: number | null;
+  reply_to: number | null;
   text: string;
 }
 
@@ -45,7 +103,6 @@ export interface CreateMessageParams {
  */
 export interface UpdateMessageParams {
   message: number;
-  user: User;
   text: string;
 }
 
@@ -55,7 +112,6 @@ export interface UpdateMessageParams {
  */
 export interface DeleteMessageParams {
   message: number;
-  user: User;
 }
 
 /**
@@ -68,7 +124,9 @@ const state: State = {
     isOn: false,
     message: "",
   },
+  token: null,
   user: null,
+  users: {},
 };
 
 export default state;